@@ -0,0 +1,521 @@
+// The Arrow C data and stream interfaces.
+// https://arrow.apache.org/docs/format/CDataInterface.html
+// https://arrow.apache.org/docs/format/CStreamInterface.html
+//
+// Arrays are read from `nanoarrow_array` objects and record batches are
+// exported into `nanoarrow_array_stream` objects allocated in R. Every
+// exported struct owns its memory through `private_data` and frees it in
+// its `release` callback.
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::ptr::{null, null_mut};
+
+// Every field is declared to match the layout even if it is never read.
+#[allow(dead_code)]
+#[repr(C)]
+pub struct ArrowSchema {
+    pub format: *const c_char,
+    pub name: *const c_char,
+    pub metadata: *const c_char,
+    pub flags: i64,
+    pub n_children: i64,
+    pub children: *mut *mut ArrowSchema,
+    pub dictionary: *mut ArrowSchema,
+    pub release: Option<unsafe extern "C" fn(*mut ArrowSchema)>,
+    pub private_data: *mut c_void,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+pub struct ArrowArray {
+    pub length: i64,
+    pub null_count: i64,
+    pub offset: i64,
+    pub n_buffers: i64,
+    pub n_children: i64,
+    pub buffers: *mut *const c_void,
+    pub children: *mut *mut ArrowArray,
+    pub dictionary: *mut ArrowArray,
+    pub release: Option<unsafe extern "C" fn(*mut ArrowArray)>,
+    pub private_data: *mut c_void,
+}
+
+#[repr(C)]
+pub struct ArrowArrayStream {
+    pub get_schema: Option<unsafe extern "C" fn(*mut ArrowArrayStream, *mut ArrowSchema) -> c_int>,
+    pub get_next: Option<unsafe extern "C" fn(*mut ArrowArrayStream, *mut ArrowArray) -> c_int>,
+    pub get_last_error: Option<unsafe extern "C" fn(*mut ArrowArrayStream) -> *const c_char>,
+    pub release: Option<unsafe extern "C" fn(*mut ArrowArrayStream)>,
+    pub private_data: *mut c_void,
+}
+
+// The schema flag of nullable fields
+const ARROW_FLAG_NULLABLE: i64 = 2;
+
+// The values of a column of a record batch
+#[derive(Debug, Clone)]
+pub enum ColumnData {
+    // strings stored end to end in `data`. `offsets` has the start of each
+    // value followed by the end of the last one
+    Utf8 {
+        offsets: Vec<i32>,
+        data: Vec<u8>,
+        valid: Vec<bool>,
+    },
+    Boolean(Vec<Option<bool>>),
+    Int32(Vec<Option<i32>>),
+    Float64(Vec<Option<f64>>),
+    // a `geoarrow.point` with separated x and y coordinates
+    Point(Vec<Option<(f64, f64)>>),
+}
+
+impl ColumnData {
+    // A string column from the UTF-8 bytes of each value
+    pub fn utf8<'a>(x: impl Iterator<Item = Option<&'a [u8]>>) -> Self {
+        let mut offsets = vec![0];
+        let mut data = Vec::new();
+        let mut valid = Vec::new();
+        for v in x {
+            data.extend_from_slice(v.unwrap_or_default());
+            offsets.push(data.len() as i32);
+            valid.push(v.is_some());
+        }
+        ColumnData::Utf8 {
+            offsets,
+            data,
+            valid,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ColumnData::Utf8 { valid, .. } => valid.len(),
+            ColumnData::Boolean(v) => v.len(),
+            ColumnData::Int32(v) => v.len(),
+            ColumnData::Float64(v) => v.len(),
+            ColumnData::Point(v) => v.len(),
+        }
+    }
+}
+
+// The name and type of a column. `metadata` is only used by points to
+// store the `crs` of their extension type.
+#[derive(Debug, Clone)]
+pub struct Field {
+    pub name: String,
+    pub format: &'static str,
+    pub metadata: Vec<(String, String)>,
+}
+
+impl Field {
+    pub fn new(name: &str, format: &'static str) -> Self {
+        Field {
+            name: name.to_string(),
+            format,
+            metadata: vec![],
+        }
+    }
+
+    // A `geoarrow.point` field. `crs` is an authority and code such as
+    // `"ESRI:102719"` when `crs_type` is `"authority_code"`, or WKT when it
+    // is `None`.
+    pub fn point(name: &str, crs: Option<String>, crs_type: Option<&str>) -> Self {
+        let ext_meta = match (crs, crs_type) {
+            (Some(crs), Some(crs_type)) => {
+                serde_json::json!({ "crs": crs, "crs_type": crs_type }).to_string()
+            }
+            (Some(crs), None) => serde_json::json!({ "crs": crs }).to_string(),
+            (None, _) => "{}".to_string(),
+        };
+        Field {
+            name: name.to_string(),
+            format: "+s",
+            metadata: vec![
+                ("ARROW:extension:name".into(), "geoarrow.point".into()),
+                ("ARROW:extension:metadata".into(), ext_meta),
+            ],
+        }
+    }
+}
+
+// Columns of equal length that share the schema of a stream
+#[derive(Debug, Clone)]
+pub struct RecordBatch {
+    pub columns: Vec<ColumnData>,
+}
+
+// Encodes schema metadata as an int32 number of pairs followed by the
+// length and bytes of each key and value
+fn encode_metadata(x: &[(String, String)]) -> Option<Vec<u8>> {
+    if x.is_empty() {
+        return None;
+    }
+    let mut out = Vec::new();
+    out.extend((x.len() as i32).to_ne_bytes());
+    for (k, v) in x {
+        out.extend((k.len() as i32).to_ne_bytes());
+        out.extend(k.as_bytes());
+        out.extend((v.len() as i32).to_ne_bytes());
+        out.extend(v.as_bytes());
+    }
+    Some(out)
+}
+
+struct SchemaPrivate {
+    format: CString,
+    name: CString,
+    metadata: Option<Vec<u8>>,
+    children: Vec<*mut ArrowSchema>,
+}
+
+unsafe extern "C" fn release_schema(schema: *mut ArrowSchema) {
+    let Some(schema) = schema.as_mut() else {
+        return;
+    };
+    if schema.release.is_none() || schema.private_data.is_null() {
+        return;
+    }
+
+    let private = Box::from_raw(schema.private_data as *mut SchemaPrivate);
+    for &child in private.children.iter() {
+        if let Some(release) = (*child).release {
+            release(child);
+        }
+        drop(Box::from_raw(child));
+    }
+
+    schema.private_data = null_mut();
+    schema.release = None;
+}
+
+fn new_schema(
+    format: &str,
+    name: &str,
+    metadata: &[(String, String)],
+    flags: i64,
+    children: Vec<ArrowSchema>,
+) -> ArrowSchema {
+    let mut private = Box::new(SchemaPrivate {
+        format: CString::new(format).unwrap_or_default(),
+        name: CString::new(name).unwrap_or_default(),
+        metadata: encode_metadata(metadata),
+        children: children
+            .into_iter()
+            .map(|c| Box::into_raw(Box::new(c)))
+            .collect(),
+    });
+
+    ArrowSchema {
+        format: private.format.as_ptr(),
+        name: private.name.as_ptr(),
+        metadata: private
+            .metadata
+            .as_ref()
+            .map_or(null(), |m| m.as_ptr() as *const c_char),
+        flags,
+        n_children: private.children.len() as i64,
+        children: private.children.as_mut_ptr(),
+        dictionary: null_mut(),
+        release: Some(release_schema),
+        private_data: Box::into_raw(private) as *mut c_void,
+    }
+}
+
+// The schema of a record batch is a struct with a child per field
+fn export_schema(fields: &[Field]) -> ArrowSchema {
+    let children = fields
+        .iter()
+        .map(|f| {
+            let children = if f.format == "+s" {
+                vec![
+                    new_schema("g", "x", &[], 0, vec![]),
+                    new_schema("g", "y", &[], 0, vec![]),
+                ]
+            } else {
+                vec![]
+            };
+            new_schema(
+                f.format,
+                &f.name,
+                &f.metadata,
+                ARROW_FLAG_NULLABLE,
+                children,
+            )
+        })
+        .collect();
+
+    new_schema("+s", "", &[], 0, children)
+}
+
+// Buffers are stored as u64 so that their values are 8-byte aligned
+struct Buffer(Vec<u64>);
+
+impl Buffer {
+    fn from_bytes(x: &[u8]) -> Self {
+        let mut out = vec![0u64; x.len() / 8 + usize::from(x.len() % 8 != 0)];
+        for (chunk, word) in x.chunks(8).zip(out.iter_mut()) {
+            let mut bytes = [0u8; 8];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            *word = u64::from_ne_bytes(bytes);
+        }
+        Buffer(out)
+    }
+
+    fn ptr(&self) -> *const c_void {
+        self.0.as_ptr() as *const c_void
+    }
+}
+
+struct ArrayPrivate {
+    buffers: Vec<Option<Buffer>>,
+    buffer_ptrs: Vec<*const c_void>,
+    children: Vec<*mut ArrowArray>,
+}
+
+unsafe extern "C" fn release_array(array: *mut ArrowArray) {
+    let Some(array) = array.as_mut() else {
+        return;
+    };
+    if array.release.is_none() || array.private_data.is_null() {
+        return;
+    }
+
+    let private = Box::from_raw(array.private_data as *mut ArrayPrivate);
+    for &child in private.children.iter() {
+        if let Some(release) = (*child).release {
+            release(child);
+        }
+        drop(Box::from_raw(child));
+    }
+
+    array.private_data = null_mut();
+    array.release = None;
+}
+
+fn new_array(
+    length: usize,
+    null_count: usize,
+    buffers: Vec<Option<Buffer>>,
+    children: Vec<ArrowArray>,
+) -> ArrowArray {
+    let buffer_ptrs = buffers
+        .iter()
+        .map(|b| b.as_ref().map_or(null(), |b| b.ptr()))
+        .collect::<Vec<_>>();
+
+    let mut private = Box::new(ArrayPrivate {
+        buffers,
+        buffer_ptrs,
+        children: children
+            .into_iter()
+            .map(|c| Box::into_raw(Box::new(c)))
+            .collect(),
+    });
+
+    ArrowArray {
+        length: length as i64,
+        null_count: null_count as i64,
+        offset: 0,
+        n_buffers: private.buffers.len() as i64,
+        n_children: private.children.len() as i64,
+        buffers: private.buffer_ptrs.as_mut_ptr(),
+        children: private.children.as_mut_ptr(),
+        dictionary: null_mut(),
+        release: Some(release_array),
+        private_data: Box::into_raw(private) as *mut c_void,
+    }
+}
+
+// The validity bitmap of a column. `None` when every value is present
+fn validity(valid: impl Iterator<Item = bool>) -> (Option<Buffer>, usize) {
+    let mut bits = Vec::new();
+    let mut n_null = 0;
+    for (i, v) in valid.enumerate() {
+        if i % 8 == 0 {
+            bits.push(0u8);
+        }
+        if v {
+            bits[i / 8] |= 1 << (i % 8);
+        } else {
+            n_null += 1;
+        }
+    }
+
+    if n_null == 0 {
+        (None, 0)
+    } else {
+        (Some(Buffer::from_bytes(&bits)), n_null)
+    }
+}
+
+fn export_column(x: &ColumnData) -> ArrowArray {
+    let n = x.len();
+    match x {
+        ColumnData::Utf8 {
+            offsets,
+            data,
+            valid,
+        } => {
+            let (valid, n_null) = validity(valid.iter().copied());
+            let offsets = offsets
+                .iter()
+                .flat_map(|o| o.to_ne_bytes())
+                .collect::<Vec<_>>();
+            let buffers = vec![
+                valid,
+                Some(Buffer::from_bytes(&offsets)),
+                Some(Buffer::from_bytes(data)),
+            ];
+            new_array(n, n_null, buffers, vec![])
+        }
+        ColumnData::Boolean(v) => {
+            let (valid, n_null) = validity(v.iter().map(|b| b.is_some()));
+            let mut bits = vec![0u8; n / 8 + usize::from(n % 8 != 0)];
+            for (i, b) in v.iter().enumerate() {
+                if b.unwrap_or_default() {
+                    bits[i / 8] |= 1 << (i % 8);
+                }
+            }
+            new_array(
+                n,
+                n_null,
+                vec![valid, Some(Buffer::from_bytes(&bits))],
+                vec![],
+            )
+        }
+        ColumnData::Int32(v) => {
+            let (valid, n_null) = validity(v.iter().map(|i| i.is_some()));
+            let values = v
+                .iter()
+                .flat_map(|i| i.unwrap_or_default().to_ne_bytes())
+                .collect::<Vec<_>>();
+            new_array(
+                n,
+                n_null,
+                vec![valid, Some(Buffer::from_bytes(&values))],
+                vec![],
+            )
+        }
+        ColumnData::Float64(v) => {
+            let (valid, n_null) = validity(v.iter().map(|d| d.is_some()));
+            let values = v
+                .iter()
+                .flat_map(|d| d.unwrap_or_default().to_ne_bytes())
+                .collect::<Vec<_>>();
+            new_array(
+                n,
+                n_null,
+                vec![valid, Some(Buffer::from_bytes(&values))],
+                vec![],
+            )
+        }
+        ColumnData::Point(v) => {
+            let (valid, n_null) = validity(v.iter().map(|p| p.is_some()));
+            let coords = |f: fn(&(f64, f64)) -> f64| {
+                let values = v
+                    .iter()
+                    .flat_map(|p| p.as_ref().map_or(f64::NAN, f).to_ne_bytes())
+                    .collect::<Vec<_>>();
+                new_array(n, 0, vec![None, Some(Buffer::from_bytes(&values))], vec![])
+            };
+            let children = vec![coords(|p| p.0), coords(|p| p.1)];
+            new_array(n, n_null, vec![valid], children)
+        }
+    }
+}
+
+// A record batch is a struct array with a child per column
+fn export_batch(x: &RecordBatch) -> ArrowArray {
+    let n = x.columns.first().map_or(0, |c| c.len());
+    let children = x.columns.iter().map(export_column).collect();
+    new_array(n, 0, vec![None], children)
+}
+
+// A released array marks the end of a stream
+fn end_of_stream() -> ArrowArray {
+    ArrowArray {
+        length: 0,
+        null_count: 0,
+        offset: 0,
+        n_buffers: 0,
+        n_children: 0,
+        buffers: null_mut(),
+        children: null_mut(),
+        dictionary: null_mut(),
+        release: None,
+        private_data: null_mut(),
+    }
+}
+
+struct StreamPrivate {
+    fields: Vec<Field>,
+    batches: std::vec::IntoIter<RecordBatch>,
+}
+
+unsafe extern "C" fn stream_get_schema(
+    stream: *mut ArrowArrayStream,
+    out: *mut ArrowSchema,
+) -> c_int {
+    let private = (*stream).private_data as *const StreamPrivate;
+    match (private.as_ref(), out.is_null()) {
+        (Some(p), false) => {
+            out.write(export_schema(&p.fields));
+            0
+        }
+        // EINVAL
+        _ => 22,
+    }
+}
+
+unsafe extern "C" fn stream_get_next(stream: *mut ArrowArrayStream, out: *mut ArrowArray) -> c_int {
+    let private = (*stream).private_data as *mut StreamPrivate;
+    match (private.as_mut(), out.is_null()) {
+        (Some(p), false) => {
+            match p.batches.next() {
+                Some(b) => out.write(export_batch(&b)),
+                None => out.write(end_of_stream()),
+            }
+            0
+        }
+        _ => 22,
+    }
+}
+
+unsafe extern "C" fn stream_get_last_error(_stream: *mut ArrowArrayStream) -> *const c_char {
+    null()
+}
+
+unsafe extern "C" fn release_stream(stream: *mut ArrowArrayStream) {
+    let Some(stream) = stream.as_mut() else {
+        return;
+    };
+    if stream.release.is_none() || stream.private_data.is_null() {
+        return;
+    }
+    drop(Box::from_raw(stream.private_data as *mut StreamPrivate));
+    stream.private_data = null_mut();
+    stream.release = None;
+}
+
+// Moves record batches into the stream at `out`. Every batch must have a
+// column of the type of each field.
+//
+// # Safety
+// `out` must point to an `ArrowArrayStream` that has been released
+pub unsafe fn export_stream(
+    fields: Vec<Field>,
+    batches: Vec<RecordBatch>,
+    out: *mut ArrowArrayStream,
+) {
+    let private = Box::new(StreamPrivate {
+        fields,
+        batches: batches.into_iter(),
+    });
+
+    out.write(ArrowArrayStream {
+        get_schema: Some(stream_get_schema),
+        get_next: Some(stream_get_next),
+        get_last_error: Some(stream_get_last_error),
+        release: Some(release_stream),
+        private_data: Box::into_raw(private) as *mut c_void,
+    });
+}
@@ -0,0 +1,409 @@
+use crate::arrow::{self, ArrowArrayStream, ColumnData, Field, RecordBatch};
+use crate::columns::{self, AttributeField, ColumnStore, Kind, Results, Store};
+use crate::error::{errors_as_robj, GeocodeError};
+use crate::find_candidates::Extent;
+use crate::schema::{self, Problem, Schema};
+use extendr_api::prelude::*;
+use serde_esri::spatial_reference::SpatialReference;
+use std::ops::Range;
+
+// The values of an attribute written directly into Arrow buffers. Strings
+// are appended to `data` and each row keeps the span of its value.
+pub enum ArrowColumn {
+    Lgl(Vec<Option<bool>>),
+    Int(Vec<Option<i32>>),
+    Dbl(Vec<Option<f64>>),
+    Str(Vec<Option<(usize, usize)>>, Vec<u8>),
+}
+
+impl ColumnStore for ArrowColumn {
+    fn new(kind: Kind, n: usize) -> Self {
+        match kind {
+            Kind::Lgl => ArrowColumn::Lgl(vec![None; n]),
+            Kind::Int => ArrowColumn::Int(vec![None; n]),
+            Kind::Dbl => ArrowColumn::Dbl(vec![None; n]),
+            Kind::Str => ArrowColumn::Str(vec![None; n], Vec::new()),
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        match self {
+            ArrowColumn::Lgl(_) => Kind::Lgl,
+            ArrowColumn::Int(_) => Kind::Int,
+            ArrowColumn::Dbl(_) => Kind::Dbl,
+            ArrowColumn::Str(..) => Kind::Str,
+        }
+    }
+
+    fn set_lgl(&mut self, row: usize, x: bool) {
+        if let ArrowColumn::Lgl(c) = self {
+            c[row] = Some(x);
+        }
+    }
+
+    fn set_int(&mut self, row: usize, x: i32) {
+        if let ArrowColumn::Int(c) = self {
+            c[row] = Some(x);
+        }
+    }
+
+    fn set_dbl(&mut self, row: usize, x: f64) {
+        if let ArrowColumn::Dbl(c) = self {
+            c[row] = Some(x);
+        }
+    }
+
+    fn set_str(&mut self, row: usize, x: &str) {
+        if let ArrowColumn::Str(spans, data) = self {
+            let start = data.len();
+            data.extend_from_slice(x.as_bytes());
+            spans[row] = Some((start, data.len()));
+        }
+    }
+
+    fn clear(&mut self, row: usize) {
+        match self {
+            ArrowColumn::Lgl(c) => c[row] = None,
+            ArrowColumn::Int(c) => c[row] = None,
+            ArrowColumn::Dbl(c) => c[row] = None,
+            ArrowColumn::Str(spans, _) => spans[row] = None,
+        }
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        match self {
+            ArrowColumn::Lgl(c) => c[to] = c[from],
+            ArrowColumn::Int(c) => c[to] = c[from],
+            ArrowColumn::Dbl(c) => c[to] = c[from],
+            ArrowColumn::Str(spans, _) => spans[to] = spans[from],
+        }
+    }
+}
+
+impl ArrowColumn {
+    // A column of an integer, double, or character vector such as the
+    // `row_keys` of the rows
+    pub fn from_robj(x: &Robj) -> Self {
+        if let Some(v) = x.as_integer_slice() {
+            ArrowColumn::Int(v.iter().map(|&i| (!i.is_na()).then_some(i)).collect())
+        } else if let Some(v) = x.as_real_slice() {
+            ArrowColumn::Dbl(v.iter().map(|&d| (!d.is_na()).then_some(d)).collect())
+        } else {
+            let values = x
+                .as_str_iter()
+                .map(|v| v.collect::<Vec<_>>())
+                .unwrap_or_default();
+            let mut res = ArrowColumn::new(Kind::Str, values.len());
+            for (i, v) in values.into_iter().enumerate() {
+                if !v.is_na() {
+                    res.set_str(i, v);
+                }
+            }
+            res
+        }
+    }
+
+    fn field(&self, name: &str) -> Field {
+        let format = match self {
+            ArrowColumn::Lgl(_) => "b",
+            ArrowColumn::Int(_) => "i",
+            ArrowColumn::Dbl(_) => "g",
+            ArrowColumn::Str(..) => "u",
+        };
+        Field::new(name, format)
+    }
+
+    // The values of `rows` as a column of a record batch
+    fn slice(&self, rows: Range<usize>) -> ColumnData {
+        match self {
+            ArrowColumn::Lgl(c) => ColumnData::Boolean(c[rows].to_vec()),
+            ArrowColumn::Int(c) => ColumnData::Int32(c[rows].to_vec()),
+            ArrowColumn::Dbl(c) => ColumnData::Float64(c[rows].to_vec()),
+            ArrowColumn::Str(spans, data) => ColumnData::utf8(
+                spans[rows]
+                    .iter()
+                    .map(|s| s.map(|(start, end)| &data[start..end])),
+            ),
+        }
+    }
+}
+
+// The points of the results. Points with a missing or NaN coordinate are
+// null. Extents are not kept.
+pub struct ArrowStore {
+    points: Vec<Option<(f64, f64)>>,
+}
+
+impl Store for ArrowStore {
+    type Column = ArrowColumn;
+
+    // the schema only depends on the fields of the locator
+    const KEEP_OTHER: bool = false;
+
+    fn new(n: usize, _with_extents: bool) -> Self {
+        ArrowStore {
+            points: vec![None; n],
+        }
+    }
+
+    fn set_location(&mut self, row: usize, x: Option<f64>, y: Option<f64>) {
+        self.points[row] = x.zip(y).filter(|(x, y)| !x.is_nan() && !y.is_nan());
+    }
+
+    fn set_extent(&mut self, _row: usize, _x: Extent) {}
+
+    fn clear(&mut self, row: usize) {
+        self.points[row] = None;
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        self.points[to] = self.points[from];
+    }
+}
+
+// The columns of the results in the order of the fields of the locator
+// followed by `extra` columns, such as the `row_key` of each row. The rows
+// of `problems` are the rows of the stream.
+#[derive(Default)]
+struct ArrowBatches {
+    fields: Vec<Field>,
+    batches: Vec<RecordBatch>,
+    n: usize,
+    problems: Vec<Problem>,
+}
+
+impl ArrowBatches {
+    // Adds a record batch for each of `rows` of the results
+    fn push(
+        &mut self,
+        results: Results<ArrowStore>,
+        extra: &[(&str, ArrowColumn)],
+        rows: &[Range<usize>],
+        esri_wkids: &[i32],
+    ) {
+        let crs = results
+            .spatial_reference()
+            .and_then(|sr| crs_of(sr, esri_wkids));
+        let offset = self.n;
+        self.n += rows.iter().map(|r| r.len()).sum::<usize>();
+        self.problems
+            .extend(results.problems().iter().map(|p| Problem {
+                row: p.row + offset,
+                ..p.clone()
+            }));
+
+        let (attributes, store, _) = results.into_parts();
+        let n = store.points.len();
+
+        // attributes without a value are strings
+        let attributes = attributes
+            .into_iter()
+            .map(|(name, c)| (name, c.unwrap_or_else(|| ArrowColumn::new(Kind::Str, n))))
+            .collect::<Vec<_>>();
+        let columns = attributes
+            .iter()
+            .map(|(name, c)| (name.as_str(), c))
+            .chain(extra.iter().map(|(name, c)| (*name, c)))
+            .collect::<Vec<_>>();
+
+        if self.fields.is_empty() {
+            self.fields = columns.iter().map(|(name, c)| c.field(name)).collect();
+            let (crs, crs_type) = crs.map_or((None, None), |(c, t)| (Some(c), t));
+            self.fields.push(Field::point("geometry", crs, crs_type));
+        }
+
+        for r in rows {
+            let mut batch = columns
+                .iter()
+                .map(|(_, c)| c.slice(r.clone()))
+                .collect::<Vec<_>>();
+            batch.push(ColumnData::Point(store.points[r.clone()].to_vec()));
+            self.batches.push(RecordBatch { columns: batch });
+        }
+    }
+
+    // Moves the batches into `stream` which is returned. Values that could
+    // not be coerced are described by its `problems` attribute.
+    fn export(self, mut stream: Robj) -> Robj {
+        let out = stream_ptr(&stream);
+        unsafe { arrow::export_stream(self.fields, self.batches, out) };
+        if !self.problems.is_empty() {
+            let _ = stream.set_attrib("problems", schema::problems_as_robj(&self.problems));
+        }
+        stream
+    }
+}
+
+// How results are returned as an Arrow stream: a newly allocated
+// `nanoarrow_array_stream`, the fields of the locator, and the WKIDs that
+// use the ESRI authority
+pub struct ArrowOutput {
+    stream: Robj,
+    fields: Vec<AttributeField>,
+    esri_wkids: Vec<i32>,
+}
+
+impl ArrowOutput {
+    // `x` is `NULL` or a list of the `stream`, the `fields` data.frame of
+    // `name` and `type`, and `esri_wkids`
+    pub fn from_robj(x: &Robj) -> Option<Self> {
+        if x.is_null() {
+            return None;
+        }
+
+        let get = |nm: &str| x.dollar(nm).unwrap_or_else(|_| ().into_robj());
+        let stream = get("stream");
+        stream_ptr(&stream);
+        let fields = match List::try_from(&get("fields")) {
+            Ok(f) => attribute_fields(&Schema::from_list(&f)),
+            Err(_) => throw_r_error("`fields` must be a data.frame"),
+        };
+
+        Some(ArrowOutput {
+            stream,
+            fields,
+            esri_wkids: wkids(&get("esri_wkids")),
+        })
+    }
+
+    // Results with `n` rows that are read into Arrow buffers
+    pub fn results(&self, n: usize) -> Results<ArrowStore> {
+        Results::new(self.fields.clone(), n, false)
+    }
+
+    // Exports the results into the stream as record batches of at most
+    // `batch_size` rows in the order of the rows
+    pub fn export(
+        self,
+        results: Results<ArrowStore>,
+        extra: &[(&str, ArrowColumn)],
+        batch_size: usize,
+    ) -> Robj {
+        let n = results.n_results();
+        let size = batch_size.max(1);
+        let rows = (0..n)
+            .step_by(size)
+            .map(|start| start..n.min(start + size))
+            .collect::<Vec<_>>();
+
+        let mut batches = ArrowBatches::default();
+        batches.push(results, extra, &rows, &self.esri_wkids);
+        batches.export(self.stream)
+    }
+}
+
+// The fields of a locator as the attributes of the results. Their columns
+// are named like the fields.
+fn attribute_fields(schema: &Schema) -> Vec<AttributeField> {
+    schema
+        .fields
+        .iter()
+        .map(|f| AttributeField::named(f.name.clone(), Kind::of(f.field_type)))
+        .collect()
+}
+
+fn wkids(x: &Robj) -> Vec<i32> {
+    if let Some(v) = x.as_integer_slice() {
+        v.to_vec()
+    } else if let Some(v) = x.as_real_slice() {
+        v.iter().map(|&w| w as i32).collect()
+    } else {
+        vec![]
+    }
+}
+
+// The `crs` of the points and its `crs_type`. Codes in `esri_wkids` use the
+// ESRI authority and all other codes EPSG. A spatial reference without a
+// code is described by its WKT.
+fn crs_of(sr: &SpatialReference, esri_wkids: &[i32]) -> Option<(String, Option<&'static str>)> {
+    match sr.latest_wkid.or(sr.wkid).map(|w| w as i32) {
+        Some(w) if esri_wkids.contains(&w) => Some((format!("ESRI:{w}"), Some("authority_code"))),
+        Some(w) => Some((format!("EPSG:{w}"), Some("authority_code"))),
+        None => sr.wkt.clone().map(|wkt| (wkt, None)),
+    }
+}
+
+// The `ArrowArrayStream` of a `nanoarrow_array_stream` that does not
+// already own any data
+fn stream_ptr(stream: &Robj) -> *mut ArrowArrayStream {
+    let msg = "`stream` must be a newly allocated `nanoarrow_array_stream`";
+    if !stream.inherits("nanoarrow_array_stream") || stream.rtype() != Rtype::ExternalPtr {
+        throw_r_error(msg);
+    }
+
+    unsafe {
+        let out = stream.external_ptr_addr::<ArrowArrayStream>();
+        match out.as_ref() {
+            Some(s) if s.release.is_none() => out,
+            _ => throw_r_error(msg),
+        }
+    }
+}
+
+// Parses /geocodeAddresses or /findAddressCandidates responses into an
+// Arrow array stream with a record batch per response. The candidates are
+// read directly into Arrow buffers using `fields`, a data.frame with the
+// `name` and `type` of each attribute such as the `candidateFields` of the
+// locator. Attributes that are not fields are dropped. When `input_id` is
+// true the position of each response is added as the `input_id` column.
+// Missing responses are skipped. Returns a list of the `stream` and the
+// `errors` of the responses that could not be parsed.
+#[extendr]
+fn parse_results_json_stream(
+    x: Strings,
+    fields: List,
+    stream: Robj,
+    esri_wkids: Robj,
+    input_id: bool,
+) -> Robj {
+    let output = ArrowOutput {
+        fields: attribute_fields(&Schema::from_list(&fields)),
+        esri_wkids: wkids(&esri_wkids),
+        stream,
+    };
+    stream_ptr(&output.stream);
+
+    let mut batches = ArrowBatches::default();
+    let mut errors = Vec::new();
+
+    for (i, body) in x.iter().enumerate().filter(|(_, b)| !b.is_na()) {
+        let res =
+            match columns::parse_into::<ArrowStore>(body.as_str(), output.fields.clone(), true) {
+                Ok(r) => r,
+                Err(e) => {
+                    errors
+                        .push(GeocodeError::from_body(body.as_str(), e).with_request(i as i32 + 1));
+                    continue;
+                }
+            };
+
+        let n = res.n_results();
+        let ids = ArrowColumn::Int(vec![Some(i as i32 + 1); n]);
+        let extra = if input_id {
+            vec![("input_id", ids)]
+        } else {
+            vec![]
+        };
+        batches.push(res, &extra, &[0..n], &output.esri_wkids);
+    }
+
+    if batches.fields.is_empty() {
+        let extra = if input_id {
+            vec![("input_id", ArrowColumn::Int(vec![]))]
+        } else {
+            vec![]
+        };
+        batches.push(output.results(0), &extra, &[], &output.esri_wkids);
+    }
+
+    list!(
+        stream = batches.export(output.stream),
+        errors = errors_as_robj(&errors, "request")
+    )
+    .into_robj()
+}
+
+extendr_module! {
+    mod arrow_results;
+    fn parse_results_json_stream;
+}
@@ -1,47 +1,44 @@
-use crate::find_candidates::Attributes as GeocodeAttrs;
-use crate::{as_sfg, parse_sr};
+use crate::arrow_results::{ArrowColumn, ArrowOutput, ArrowStore};
+use crate::cache::{cache_key, GeocodeCache};
+use crate::cassette::Cassette;
+use crate::columns::{self, Results};
+use crate::convert::{self, ConversionError, Converted, OrThrow};
+use crate::error::{errors_as_robj, GeocodeError};
+use crate::find_candidates::ATTRIBUTE_FIELDS;
+use crate::geoarrow;
+use crate::input_fields::InputFields;
+use crate::journal::{Fingerprint, Journal};
+use crate::parse_custom_attrs::CustomResults;
+use crate::retry::{is_rejected, RetryPolicy};
+use crate::transport::{
+    attempts_as_robj, encoded_len, endpoint_url, form_size, FormBody, Reply, Transport,
+};
 use extendr_api::prelude::*;
 use serde::{Deserialize, Serialize};
-use serde_esri::{geometry::EsriPoint, spatial_reference::SpatialReference};
+use serde_esri::geometry::EsriPoint;
+use serde_json::{json, Value};
 use serde_with::skip_serializing_none;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct GeocodeAdddressesResults {
-    #[serde(rename = "spatialReference")]
-    pub spatial_reference: SpatialReference,
-    pub locations: Vec<Location>,
-}
-
-#[skip_serializing_none]
-#[derive(Debug, Deserialize, Serialize, Clone)]
-pub struct Location {
-    pub address: Option<String>,
-    pub location: Option<EsriPoint>,
-    pub score: f64,
-    pub attributes: GeocodeAttrs,
-}
-
+// A record of /geocodeAddresses. The fields are keyed by the names of the
+// locator's `addressFields` and missing fields are omitted.
 #[skip_serializing_none]
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Address {
     objectid: i32,
-    #[serde(rename = "singleLine")]
-    single_line: Option<String>,
-    address: Option<String>,
-    address2: Option<String>,
-    address3: Option<String>,
-    neighborhood: Option<String>,
-    city: Option<String>,
-    subregion: Option<String>,
-    region: Option<String>,
-    postal: Option<String>,
-    #[serde(rename = "postalExt")]
-    postal_ext: Option<String>,
-    #[serde(rename = "countryCode")]
-    country_code: Option<String>,
+    #[serde(flatten)]
+    fields: BTreeMap<String, String>,
     location: Option<EsriPoint>,
 }
 
+impl Address {
+    // An address without any field cannot be matched by the service
+    fn is_empty(&self) -> bool {
+        self.fields.is_empty() && self.location.is_none()
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Record {
     attributes: Address,
@@ -52,237 +49,1187 @@ pub struct Records {
     records: Vec<Record>,
 }
 
-// TODO have an argument for object ID
-#[extendr]
-pub fn create_records(
-    object_id: Integers,
-    single_line: Nullable<Strings>,
-    address: Nullable<Strings>,
-    address2: Nullable<Strings>,
-    address3: Nullable<Strings>,
-    neighborhood: Nullable<Strings>,
-    city: Nullable<Strings>,
-    subregion: Nullable<Strings>,
-    region: Nullable<Strings>,
-    postal: Nullable<Strings>,
-    postal_ext: Nullable<Strings>,
-    country_code: Nullable<Strings>,
-    location: Nullable<List>,
-    sr: Robj,
-    n: i32,
-) -> String {
-    let n = n as usize;
-    let spatial_ref = parse_sr(sr);
-    let mut record_vec: Vec<Record> = Vec::with_capacity(n);
-
-    for i in 0..n {
-        let single = match single_line {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
-            }
-            Null => None,
-        };
+// Missing and blank values are `None` so that they are omitted from the record
+fn str_elt(x: &Strings, i: usize) -> Option<String> {
+    let elt = x.elt(i);
+    if elt.is_na() || elt.trim().is_empty() {
+        return None;
+    }
+    Some(elt.to_string())
+}
 
-        let addr = match address {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
-            }
-            Null => None,
-        };
+// The address fields that are used to create `Records`
+// Each field is optional. If present, they must all have the same length.
+struct AddressColumns {
+    // the locator field and values of each input column
+    fields: Vec<(String, Strings)>,
+    location: Option<Vec<Option<EsriPoint>>>,
+}
 
-        let addr2 = match address2 {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
-            }
-            Null => None,
-        };
+// Converts the `location` column. This is a list of points or WKB, or a
+// `nanoarrow_array` of geoarrow points. A spatial reference is required
+// unless the array has a `crs`.
+fn location_column(location: Option<Robj>, sr: &Robj) -> Converted<Option<Vec<Option<EsriPoint>>>> {
+    let location = match location {
+        Some(l) => l,
+        None => return Ok(None),
+    };
 
-        let addr3 = match address3 {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
-            }
-            Null => None,
-        };
+    let sr = convert::optional_spatial_reference("sr", sr)?;
 
-        let nbh = match neighborhood {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
-            }
-            Null => None,
-        };
+    if location.inherits("nanoarrow_array") {
+        return geoarrow::points("location", &location, sr.as_ref()).map(Some);
+    }
 
-        let cty = match city {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
-            }
-            Null => None,
-        };
+    let location = List::try_from(location.clone()).map_err(|_| {
+        ConversionError::new("location", "must be a list of points").found(&location)
+    })?;
 
-        let sub = match subregion {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
-            }
-            Null => None,
-        };
+    match sr {
+        Some(sr) => convert::points("location", location, &sr).map(Some),
+        None => Err(ConversionError::new(
+            "sr",
+            "must be provided when `location` is",
+        )),
+    }
+}
 
-        let reg = match region {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
-            }
-            Null => None,
-        };
+impl AddressColumns {
+    // Creates the address columns from a data.frame. Each column other
+    // than `location` is matched to one of the locator's input fields.
+    fn from_list(x: &List, fields: &InputFields, sr: &Robj) -> Converted<Self> {
+        let mut cols: Vec<(String, Strings)> = Vec::new();
+        let mut location = None;
 
-        let post = match postal {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
+        for (name, col) in x.iter() {
+            if col.is_null() {
+                continue;
             }
-            Null => None,
-        };
 
-        let postx = match postal_ext {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
+            if name == "location" {
+                location = Some(col);
+                continue;
             }
-            Null => None,
-        };
 
-        let cc = match country_code {
-            NotNull(ref a) => {
-                let s = a.elt(i);
-                Some(s.to_string())
+            let field = fields.resolve(name)?;
+            if cols.iter().any(|(f, _)| *f == field) {
+                let msg = format!("refers to the `{field}` field more than once");
+                return Err(ConversionError::new(name, msg));
             }
-            Null => None,
-        };
 
-        let loc = match location {
-            // If not-null that means the spatial ref also has to not be null
-            NotNull(ref a) => {
-                let loc = Doubles::try_from(a.elt(i).unwrap()).unwrap();
-                let p = EsriPoint {
-                    x: loc[0].inner(),
-                    y: loc[1].inner(),
-                    z: None,
-                    m: None,
-                    spatialReference: Some(spatial_ref.clone().unwrap()),
-                };
+            let values = Strings::try_from(col.clone()).map_err(|_| {
+                ConversionError::new(name, "must be a character vector").found(&col)
+            })?;
+            cols.push((field, values));
+        }
 
-                Some(p)
-            }
-            Null => None,
-        };
+        Ok(AddressColumns {
+            fields: cols,
+            location: location_column(location, sr)?,
+        })
+    }
+
+    // Every column that is present must have `n` elements
+    fn check_len(&self, n: usize) -> Converted<()> {
+        for (field, col) in self.fields.iter() {
+            convert::check_len(field, col.len(), n)?;
+        }
+
+        if let Some(l) = &self.location {
+            convert::check_len("location", l.len(), n)?;
+        }
+
+        Ok(())
+    }
+
+    fn record(&self, i: usize, objectid: i32) -> Record {
+        let loc = self
+            .location
+            .as_ref()
+            .and_then(|l| l.get(i).cloned().flatten());
+
+        let fields = self
+            .fields
+            .iter()
+            .filter_map(|(f, col)| Some((f.clone(), str_elt(col, i)?)))
+            .collect();
 
-        let oid = object_id[i].inner();
         let record = Address {
-            objectid: oid,
-            single_line: single,
-            address: addr,
-            address2: addr2,
-            address3: addr3,
-            neighborhood: nbh,
-            city: cty,
-            subregion: sub,
-            region: reg,
-            postal: post,
-            postal_ext: postx,
-            country_code: cc,
+            objectid,
+            fields,
             location: loc,
         };
 
-        // push record into vec
-        record_vec.push(Record { attributes: record });
+        Record { attributes: record }
+    }
+
+    // The 1-based rows whose location is empty and therefore not sent
+    fn skipped_locations(&self) -> Vec<i32> {
+        self.location
+            .as_deref()
+            .map(convert::empty_points)
+            .unwrap_or_default()
+    }
+
+    // The rows where every address field is missing
+    fn empty_rows(&self, n: usize) -> Vec<usize> {
+        (0..n)
+            .filter(|&i| self.record(i, 0).attributes.is_empty())
+            .collect()
+    }
+}
+
+// Normalizes an address value so that trivially different spellings of
+// the same address are identical. The value is NFKC normalized, which
+// composes accents and folds full-width forms to ASCII, before letters are
+// lower cased. Zero-width characters are removed and runs of whitespace
+// become a single space.
+fn normalize_value(x: &str) -> String {
+    let folded = x
+        .nfkc()
+        .filter(|c| !matches!(c, '\u{200B}'..='\u{200D}' | '\u{FEFF}'))
+        .flat_map(char::to_lowercase)
+        .collect::<String>();
+
+    folded.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// Identifies the rows that have the same address as an earlier row
+struct Duplicates {
+    // the row whose result is used for each row. Distinct rows refer to themselves
+    first: Vec<usize>,
+}
+
+impl Duplicates {
+    // Rows are compared on their normalized fields and location.
+    // `skip` rows are never considered duplicates.
+    fn new(cols: &AddressColumns, n: usize, skip: &[bool]) -> Self {
+        let mut seen: HashMap<String, usize> = HashMap::new();
+
+        let first = (0..n)
+            .map(|i| {
+                if skip.get(i).copied().unwrap_or(false) {
+                    return i;
+                }
+
+                let addr = cols.record(i, 0).attributes;
+                let fields = addr
+                    .fields
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), normalize_value(v)))
+                    .collect::<BTreeMap<_, _>>();
+                let key = serde_json::to_string(&(fields, &addr.location)).unwrap_or_default();
+
+                *seen.entry(key).or_insert(i)
+            })
+            .collect();
+
+        Duplicates { first }
+    }
+
+    fn is_duplicate(&self, i: usize) -> bool {
+        self.first[i] != i
+    }
+
+    fn n_duplicates(&self) -> usize {
+        (0..self.first.len())
+            .filter(|&i| self.is_duplicate(i))
+            .count()
+    }
+
+    // The duplicates of each distinct row that are not in `exclude`
+    fn copies(&self, exclude: &[bool]) -> HashMap<usize, Vec<usize>> {
+        let mut copies: HashMap<usize, Vec<usize>> = HashMap::new();
+        for (i, &f) in self.first.iter().enumerate() {
+            if f != i && !exclude[i] {
+                copies.entry(f).or_default().push(i);
+            }
+        }
+        copies
     }
+}
+
+// The number of rows of a data.frame of addresses
+fn n_rows(x: &List) -> usize {
+    x.values().next().map_or(0, |c| c.len())
+}
+
+// Creates the `addresses` of a /geocodeAddresses request. The columns of
+// `addresses` are validated against the locator `fields`, the output of
+// `geocode_server_metadata()`, and serialized with the locator's field
+// names. Rows where every address field is missing are omitted. See
+// `empty_address_rows()`.
+#[extendr]
+pub fn create_records(object_id: Integers, addresses: List, fields: Robj, sr: Robj) -> String {
+    let fields = InputFields::from_robj(&fields).or_throw();
+    let cols = AddressColumns::from_list(&addresses, &fields, &sr).or_throw();
+    let n = n_rows(&addresses);
+
+    cols.check_len(n).or_throw();
+    convert::check_len("object_id", object_id.len(), n).or_throw();
+
+    let record_vec = (0..n)
+        .map(|i| cols.record(i, object_id[i].inner()))
+        .filter(|r| !r.attributes.is_empty())
+        .collect::<Vec<_>>();
 
     let recs = Records {
         records: record_vec,
     };
 
-    serde_json::to_string(&recs).unwrap()
+    match serde_json::to_string(&recs) {
+        Ok(json) => json,
+        Err(e) => throw_r_error(format!("Failed to serialize records: {e}")),
+    }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct ErrorCode {
-    code: i32,
-    extended_code: Option<i32>,
-    message: Option<String>,
-    details: Option<Vec<String>>,
+// The 1-based rows of a table of addresses where every field is missing.
+// These are not sent to the service.
+#[extendr]
+pub fn empty_address_rows(addresses: List, fields: Robj, sr: Robj) -> Vec<i32> {
+    let fields = InputFields::from_robj(&fields).or_throw();
+    let cols = AddressColumns::from_list(&addresses, &fields, &sr).or_throw();
+    let n = n_rows(&addresses);
+    cols.check_len(n).or_throw();
+    cols.empty_rows(n)
+        .into_iter()
+        .map(|i| i as i32 + 1)
+        .collect()
 }
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct ErrorMsg {
-    error: ErrorCode,
+
+// The result of a row that was not sent because it has no address. Only
+// the fields that every locator returns are included.
+fn unmatched_location(result_id: i32) -> Value {
+    json!({
+        "address": "",
+        "score": 0,
+        "attributes": { "ResultID": result_id, "Status": "U", "Score": 0 }
+    })
+}
+
+// A /geocodeAddresses response with the given locations
+fn locations_body(sr: Option<Value>, locations: Vec<Value>) -> String {
+    let sr = sr.unwrap_or_else(|| json!({ "wkid": 4326 }));
+    json!({ "spatialReference": sr, "locations": locations }).to_string()
 }
 
+// The locations of a /geocodeAddresses response
+fn response_locations(body: &str) -> Vec<Value> {
+    match serde_json::from_str::<Value>(body) {
+        Ok(Value::Object(mut res)) => match res.remove("locations") {
+            Some(Value::Array(locs)) => locs,
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+// The 1-based input row of a location
+fn result_id(loc: &Value) -> Option<i32> {
+    loc.get("attributes")?
+        .get("ResultID")?
+        .as_i64()
+        .and_then(|id| i32::try_from(id).ok())
+}
+
+// Creates a /geocodeAddresses response in which each of `object_ids` is
+// unmatched. The spatial reference is taken from the response `body` so
+// that the results can be combined with it. Otherwise WGS84 is used.
 #[extendr]
-pub fn parse_location_json(x: &str) -> Robj {
-    let parsed = serde_json::from_str::<GeocodeAdddressesResults>(x);
+pub fn unmatched_locations_json(object_ids: Vec<i32>, body: Nullable<&str>) -> String {
+    let sr = body
+        .into_option()
+        .and_then(|b| serde_json::from_str::<Value>(b).ok())
+        .and_then(|b| b.get("spatialReference").cloned());
 
-    match parsed {
-        Ok(p) => {
-            let n = p.locations.len();
-            let mut location_res = List::new(n);
+    let locations = object_ids
+        .iter()
+        .map(|&id| unmatched_location(id))
+        .collect();
+    locations_body(sr, locations)
+}
 
-            let location_attrs = p
-                .locations
-                .into_iter()
-                .enumerate()
-                .map(|(i, pi)| {
-                    if let Some(loc) = pi.location {
-                        let _ = location_res.set_elt(i, as_sfg(loc));
-                    } else {
-                        let empty_point = Doubles::from_values([Rfloat::na(), Rfloat::na()])
-                            .into_robj()
-                            .set_class(&["XY", "POINT", "sfg"])
-                            .unwrap()
-                            .to_owned();
-
-                        let _ = location_res.set_elt(i, empty_point);
-                    }
-                    pi.attributes
-                })
-                .collect::<Vec<_>>();
+// The keys that the caller identifies the input rows by. `values` is the
+// supplied vector and `keys` are its elements as strings, which is how
+// they are recorded in the journal.
+struct RowKeys {
+    values: Robj,
+    keys: Vec<String>,
+}
+
+impl RowKeys {
+    fn from_robj(x: Robj, n: usize) -> Converted<Option<Self>> {
+        let keys = convert::row_keys("row_keys", &x, n)?;
+        Ok(keys.map(|keys| RowKeys { values: x, keys }))
+    }
 
-            let res = location_attrs.into_dataframe().unwrap();
-            let location_attrs = res.as_robj().clone();
+    // The keys of the 1-based `rows` with the type of the supplied keys
+    fn subset(&self, rows: &[i32]) -> Robj {
+        let n = self.keys.len();
+        let idx = rows
+            .iter()
+            .map(move |&r| usize::try_from(r - 1).ok().filter(|&r| r < n));
 
-            list!(
-                attributes = location_attrs,
-                locations = location_res,
-                sr = extendr_api::serializer::to_robj(&p.spatial_reference).unwrap()
+        if let Some(x) = self.values.as_integer_slice() {
+            Integers::from_values(idx.map(|r| r.map_or(Rint::na(), |r| Rint::from(x[r]))))
+                .into_robj()
+        } else if let Some(x) = self.values.as_real_slice() {
+            Doubles::from_values(idx.map(|r| r.map_or(Rfloat::na(), |r| Rfloat::from(x[r]))))
+                .into_robj()
+        } else {
+            Strings::from_values(
+                idx.map(|r| r.map_or(Rstr::na(), |r| Rstr::from(self.keys[r].as_str()))),
             )
             .into_robj()
         }
-        Err(ee) => {
-            match serde_json::from_str::<ErrorMsg>(x) {
-                Ok(e) => {
-                    let err = e.error;
-                    let fmt = format!(
-                        "Error occured parsing response:\n{}: {} {}",
-                        err.code,
-                        err.message.unwrap_or("".into()),
-                        err.details.unwrap_or(vec![]).join(" ")
-                    );
-                    eprintln!("{fmt}");
+    }
+}
+
+// The rows that the keys of a journal entry are at now. `rows` are the
+// rows the keys were journaled at.
+fn keyed_rows(
+    rows: &[usize],
+    keys: &[String],
+    key_rows: &HashMap<&str, usize>,
+) -> std::result::Result<Vec<usize>, String> {
+    if keys.len() != rows.len() {
+        return Err(String::from(
+            "the row keys of a chunk do not match its rows",
+        ));
+    }
+
+    keys.iter()
+        .map(|k| {
+            key_rows
+                .get(k.as_str())
+                .copied()
+                .ok_or_else(|| format!("row key `{k}` is not one of `row_keys`"))
+        })
+        .collect()
+}
+
+// Moves the locations of a response from the rows in `from` to the rows
+// at the same position in `to` by rewriting their ResultIDs
+fn remap_result_ids(response: &mut Value, from: &[usize], to: &[usize]) {
+    let moved = from
+        .iter()
+        .zip(to)
+        .map(|(&f, &t)| (f as i64 + 1, t as i64 + 1))
+        .collect::<HashMap<_, _>>();
+
+    let locations = response.get_mut("locations").and_then(|l| l.as_array_mut());
+
+    for loc in locations.into_iter().flatten() {
+        let id = loc
+            .get("attributes")
+            .and_then(|a| a.get("ResultID"))
+            .and_then(|id| id.as_i64());
+        let attrs = loc.get_mut("attributes").and_then(|a| a.as_object_mut());
+
+        if let (Some(new), Some(attrs)) = (id.and_then(|id| moved.get(&id)), attrs) {
+            attrs.insert(String::from("ResultID"), json!(new));
+        }
+    }
+}
+
+// How the locations of a job are parsed. Locators with custom fields are
+// decoded using their `candidateFields`. Results that are returned as an
+// Arrow stream are read directly into Arrow buffers.
+enum Rows {
+    Standard(Results),
+    Custom(CustomResults),
+    Arrow(Results<ArrowStore>, ArrowOutput),
+}
+
+// The results of a job with one row per input row. Each response is read
+// into the rows of its ResultIDs as soon as it arrives. The ObjectID of
+// every record is its 1-based row so the ResultID identifies the input row
+// regardless of the chunk it was sent in.
+struct AlignedResults {
+    rows: Rows,
+    // ResultIDs that were returned more than once or that are not an input row
+    duplicates: Vec<i32>,
+    // the caller supplied keys that become the `row_key` column
+    row_keys: Option<Robj>,
+    // the number of rows of each record batch of an Arrow stream
+    batch_size: usize,
+}
+
+impl AlignedResults {
+    fn new(
+        n: usize,
+        candidate_fields: Option<List>,
+        arrow: Option<ArrowOutput>,
+        row_keys: Option<Robj>,
+        batch_size: usize,
+    ) -> Self {
+        let rows = match (arrow, candidate_fields) {
+            (Some(output), _) => Rows::Arrow(output.results(n), output),
+            (None, Some(fields)) => Rows::Custom(CustomResults::new(&fields, n)),
+            (None, None) => Rows::Standard(Results::aligned(&ATTRIBUTE_FIELDS[..], n)),
+        };
+
+        AlignedResults {
+            rows,
+            duplicates: Vec::new(),
+            row_keys,
+            batch_size,
+        }
+    }
+
+    // Reads a response. Only the first location of a ResultID is kept.
+    fn read(&mut self, body: &str) -> Result<(), GeocodeError> {
+        let unplaced = match &mut self.rows {
+            Rows::Standard(r) => r
+                .read_aligned(body)
+                .map_err(|e| GeocodeError::from_body(body, e))?,
+            Rows::Custom(r) => r.read_aligned(body)?,
+            Rows::Arrow(r, _) => r
+                .read_aligned(body)
+                .map_err(|e| GeocodeError::from_body(body, e))?,
+        };
+
+        self.duplicates.extend(unplaced);
+        Ok(())
+    }
+
+    fn n_results(&self) -> usize {
+        match &self.rows {
+            Rows::Standard(r) => r.n_results(),
+            Rows::Custom(r) => r.n_results(),
+            Rows::Arrow(r, _) => r.n_results(),
+        }
+    }
+
+    fn is_filled(&self, row: usize) -> bool {
+        match &self.rows {
+            Rows::Standard(r) => r.is_filled(row),
+            Rows::Custom(r) => r.is_filled(row),
+            Rows::Arrow(r, _) => r.is_filled(row),
+        }
+    }
+
+    fn copy_row(&mut self, from: usize, to: usize) {
+        match &mut self.rows {
+            Rows::Standard(r) => r.copy_row(from, to),
+            Rows::Custom(r) => r.copy_row(from, to),
+            Rows::Arrow(r, _) => r.copy_row(from, to),
+        }
+    }
+
+    fn spatial_reference(&self) -> Option<Value> {
+        match &self.rows {
+            Rows::Standard(r) => r
+                .spatial_reference()
+                .and_then(|sr| serde_json::to_value(sr).ok()),
+            Rows::Custom(r) => r.spatial_reference().cloned(),
+            Rows::Arrow(r, _) => r
+                .spatial_reference()
+                .and_then(|sr| serde_json::to_value(sr).ok()),
+        }
+    }
+
+    // The 1-based rows that no location was read into
+    fn missing(&self) -> Vec<i32> {
+        (0..self.n_results())
+            .filter(|&i| !self.is_filled(i))
+            .map(|i| i as i32 + 1)
+            .collect()
+    }
+
+    // A list of the attributes, locations, and the spatial reference which
+    // is turned into an sf object in R, or the Arrow stream of the results.
+    // The ResultID of each row is its position and its key is the last
+    // column of the attributes. When no row was geocoded the results are in
+    // WGS84.
+    fn into_robj(mut self) -> Robj {
+        if self.spatial_reference().is_none() {
+            let _ = self.read(&locations_body(None, vec![]));
+        }
+
+        let res = match self.rows {
+            Rows::Standard(mut r) => {
+                r.number_rows("ResultID");
+                r.into_robj()
+            }
+            Rows::Custom(r) => r.into_robj(),
+            Rows::Arrow(mut r, output) => {
+                r.number_rows("ResultID");
+                let keys = self.row_keys.as_ref().map(ArrowColumn::from_robj);
+                let extra = keys.into_iter().map(|k| ("row_key", k)).collect::<Vec<_>>();
+                return output.export(r, &extra, self.batch_size);
+            }
+        };
+
+        match self.row_keys {
+            Some(keys) => add_attribute_column(res, "row_key", keys),
+            None => res,
+        }
+    }
+}
+
+// Adds a column to the attributes data.frame of a list of results
+fn add_attribute_column(res: Robj, name: &str, column: Robj) -> Robj {
+    let mut list = match List::try_from(&res) {
+        Ok(l) => l,
+        Err(_) => return res,
+    };
+
+    let i = list
+        .names()
+        .and_then(|mut nms| nms.position(|nm| nm == "attributes"));
+
+    if let Some(i) = i {
+        if let Ok(attributes) = list.elt(i) {
+            let _ = list.set_elt(i, convert::push_element(attributes, name, column));
+        }
+    }
+    list.into_robj()
+}
+
+// Parses a /geocodeAddresses response. The locations are read directly
+// into the columns of the attributes data.frame.
+#[extendr]
+pub fn parse_location_json(x: &str) -> Robj {
+    match columns::parse_results(x, &ATTRIBUTE_FIELDS[..], false) {
+        Ok(res) => res.into_robj(),
+        Err(ee) => GeocodeError::from_body(x, ee).into_robj(),
+    }
+}
+
+// The parameters of a request that are shared by every batch
+fn params_form(params: &Strings) -> FormBody {
+    match params.names() {
+        Some(nms) => nms
+            .zip(params.iter())
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>(),
+        None => vec![],
+    }
+}
+
+// The form of a /geocodeAddresses request for `rows`.
+// The object IDs are the row positions so they are unique across chunks
+fn records_form(cols: &AddressColumns, rows: &[usize], shared: &FormBody) -> FormBody {
+    let records = rows
+        .iter()
+        .map(|&i| cols.record(i, i as i32 + 1))
+        .collect::<Vec<_>>();
+
+    let mut body = vec![
+        (String::from("f"), String::from("json")),
+        (
+            String::from("addresses"),
+            serde_json::to_string(&Records { records }).unwrap_or_default(),
+        ),
+    ];
+    body.extend(shared.iter().cloned());
+    body
+}
+
+// The rows of a batch and the size in bytes of its request body
+#[derive(Debug, Clone)]
+struct Chunk {
+    rows: Vec<usize>,
+    bytes: usize,
+}
+
+// Partitions `rows` into chunks of at most `batch_size` rows. When
+// `max_bytes` is set a chunk is also closed before its body would exceed
+// it. A record that is larger than `max_bytes` on its own is sent alone.
+fn partition(
+    cols: &AddressColumns,
+    rows: &[usize],
+    shared: &FormBody,
+    batch_size: usize,
+    max_bytes: Option<usize>,
+) -> Vec<Chunk> {
+    // the body without any records
+    let base = form_size(&records_form(cols, &[], shared));
+    // records are separated by an encoded comma
+    let sep = encoded_len(",");
+
+    let mut chunks: Vec<Chunk> = Vec::new();
+    let mut current = Chunk {
+        rows: Vec::new(),
+        bytes: base,
+    };
+
+    for &i in rows {
+        let record = serde_json::to_string(&cols.record(i, i as i32 + 1)).unwrap_or_default();
+        let size = encoded_len(&record);
+        let added = if current.rows.is_empty() {
+            size
+        } else {
+            size + sep
+        };
+
+        let full = current.rows.len() >= batch_size
+            || max_bytes.map_or(false, |m| current.bytes + added > m);
+
+        if full && !current.rows.is_empty() {
+            chunks.push(std::mem::replace(
+                &mut current,
+                Chunk {
+                    rows: Vec::new(),
+                    bytes: base,
+                },
+            ));
+            current.bytes += size;
+        } else {
+            current.bytes += added;
+        }
+
+        current.rows.push(i);
+    }
+
+    if !current.rows.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+// The number of rows and bytes of each chunk
+fn layout_as_robj(chunks: &[Chunk]) -> Robj {
+    let rows = chunks
+        .iter()
+        .map(|c| c.rows.iter().map(|&i| i as i32 + 1).collect::<Vec<_>>())
+        .collect::<List>();
+
+    list!(
+        chunk = (1..=chunks.len() as i32).collect::<Vec<_>>(),
+        n = chunks
+            .iter()
+            .map(|c| c.rows.len() as i32)
+            .collect::<Vec<_>>(),
+        bytes = chunks.iter().map(|c| c.bytes as f64).collect::<Vec<_>>(),
+        rows = rows
+    )
+    .into_robj()
+}
+
+// For every row, the 1-based row with the same normalized address whose
+// result it shares. Distinct addresses refer to their own row.
+#[extendr]
+pub fn duplicate_address_rows(addresses: List, fields: Robj, sr: Robj) -> Vec<i32> {
+    let fields = InputFields::from_robj(&fields).or_throw();
+    let cols = AddressColumns::from_list(&addresses, &fields, &sr).or_throw();
+    let n = n_rows(&addresses);
+    cols.check_len(n).or_throw();
+
+    let mut empty = vec![false; n];
+    for i in cols.empty_rows(n) {
+        empty[i] = true;
+    }
+
+    Duplicates::new(&cols, n, &empty)
+        .first
+        .into_iter()
+        .map(|i| i as i32 + 1)
+        .collect()
+}
+
+// A set of rows that are sent in a single request
+#[derive(Debug, Clone)]
+struct Batch {
+    // the 1-based index of the chunk the rows were partitioned into
+    chunk: i32,
+    rows: Vec<usize>,
+    // how the chunk was rejected when this batch is a part of it
+    rejection: Option<Rejection>,
+}
+
+// The code and message of a request that was rejected because of its content
+type Rejection = (Option<i32>, String);
+
+fn rejection(reply: &Reply) -> Option<Rejection> {
+    reply
+        .attempts
+        .last()
+        .filter(|a| is_rejected(a))
+        .map(|a| (a.code.or(a.status.map(i32::from)), a.message.clone()))
+}
+
+// The batches of a chunk that were sent in a single round
+#[derive(Debug, Default)]
+struct Round {
+    n: usize,
+    // batches of a single row
+    n_single: usize,
+    // batches that were rejected in the same way as the whole chunk
+    n_same: usize,
+}
+
+impl Round {
+    // A shared parameter, such as an invalid `outSR`, makes the service
+    // reject every subset of the rows. When all of the batches a chunk was
+    // split into fail like the chunk did, the chunk is not split further.
+    // Four batches are required unless they are single rows so that a few
+    // malformed rows that landed in different halves are still isolated.
+    fn is_uniform(&self) -> bool {
+        self.n_same == self.n && (self.n >= 4 || self.n_single == self.n)
+    }
+}
+
+// Everything that is collected while running a geocoding job
+struct JobResults {
+    results: AlignedResults,
+    // chunks whose rows could not be geocoded at all
+    errors: Vec<GeocodeError>,
+    // individual rows that were rejected by the service
+    error_rows: Vec<GeocodeError>,
+    // every reply alongside the chunk it belongs to
+    replies: Vec<(i32, Reply)>,
+    // where completed chunks are recorded
+    journal: Option<Journal>,
+    // the locations that are written to the cache when it is used
+    storable: Option<Vec<Value>>,
+    // the caller supplied keys of the rows that are journaled with them
+    row_keys: Option<Vec<String>>,
+}
+
+impl JobResults {
+    // Sends the batches and collects their results. When a batch is rejected
+    // because of its content it is split in half and sent again. This repeats
+    // until only the rows that the service cannot process remain.
+    fn run(
+        &mut self,
+        transport: &Transport,
+        mut batches: Vec<Batch>,
+        form: impl Fn(&[usize]) -> FormBody,
+    ) {
+        while !batches.is_empty() {
+            let forms = batches.iter().map(|b| form(&b.rows)).collect::<Vec<_>>();
+
+            let replies = match transport.post_forms(forms) {
+                Ok(r) => r,
+                Err(e) => throw_r_error(format!("Failed to send requests: {e}")),
+            };
+
+            let mut rounds: HashMap<i32, Round> = HashMap::new();
+            for (batch, reply) in batches.iter().zip(replies.iter()) {
+                let round = rounds.entry(batch.chunk).or_default();
+                round.n += 1;
+                round.n_single += usize::from(batch.rows.len() == 1);
+                if batch.rejection.is_some() && rejection(reply) == batch.rejection {
+                    round.n_same += 1;
+                }
+            }
+
+            let mut to_split = Vec::new();
+            // chunks that were reported as a whole in this round
+            let mut reported = HashSet::new();
+
+            for (batch, reply) in batches.into_iter().zip(replies) {
+                match reply.success() {
+                    Some(body) => match self.results.read(body) {
+                        Ok(()) => {
+                            if let Some(j) = self.journal.as_ref() {
+                                let keys = self.row_keys.as_deref();
+                                if let Err(e) = j.record(&batch.rows, body, keys) {
+                                    throw_r_error(format!("Failed to write to journal: {e}"));
+                                }
+                            }
+                            if let Some(locs) = self.storable.as_mut() {
+                                locs.extend(response_locations(body));
+                            }
+                        }
+                        Err(err) => self.errors.push(err.with_request(batch.chunk)),
+                    },
+                    None => {
+                        let rejected = rejection(&reply);
+                        let err = GeocodeError::from_reply(&reply);
+                        let uniform = rounds[&batch.chunk].is_uniform();
+
+                        if uniform {
+                            // the error is reported once for the whole chunk
+                            if reported.insert(batch.chunk) {
+                                if let Some(e) = err {
+                                    self.errors.push(e.with_request(batch.chunk));
+                                }
+                            }
+                        } else if rejected.is_some() && batch.rows.len() > 1 {
+                            // bisect the batch
+                            let mid = batch.rows.len() / 2;
+                            let (left, right) = batch.rows.split_at(mid);
+                            let rejection = batch.rejection.or(rejected);
+                            to_split.push(Batch {
+                                chunk: batch.chunk,
+                                rows: left.to_vec(),
+                                rejection: rejection.clone(),
+                            });
+                            to_split.push(Batch {
+                                chunk: batch.chunk,
+                                rows: right.to_vec(),
+                                rejection,
+                            });
+                        } else if rejected.is_some() {
+                            // the ObjectID is the 1-based row position
+                            let object_id = batch.rows[0] as i32 + 1;
+                            if let Some(e) = err {
+                                self.error_rows.push(e.with_request(object_id));
+                            }
+                        } else if let Some(e) = err {
+                            self.errors.push(e.with_request(batch.chunk));
+                        }
+                    }
+                }
+
+                self.replies.push((batch.chunk, reply));
+            }
+
+            batches = to_split;
+        }
+    }
+}
+
+// Geocodes an entire table of addresses.
+// The records are partitioned into chunks of `batch_size` which are
+// posted with at most `max_active` concurrent requests. The results of
+// every chunk are combined and returned in the order of the input rows.
+// When `journal` is a directory, each completed chunk is written to it and
+// chunks that were completed by a previous call are not sent again.
+// `candidate_fields` decodes the attributes of a locator with custom fields.
+// See `parse_custom_location_json_()`.
+// When `arrow` is a list of a `stream`, the `fields` of the locator, and
+// `esri_wkids`, the responses are read into Arrow buffers and the results
+// are the stream with record batches of `batch_size` rows.
+// Results are only written to the cache or journal when `params` contains
+// `forStorage=true`. `on_layout` is called with the layout of the chunks
+// before they are sent. Rows that are completed by the journal, found in
+// the cache, or duplicated are not part of it.
+#[extendr]
+#[allow(clippy::too_many_arguments)]
+pub fn geocode_addresses_rs(
+    service_url: &str,
+    addresses: List,
+    fields: Robj,
+    candidate_fields: Nullable<List>,
+    sr: Robj,
+    params: Strings,
+    batch_size: i32,
+    max_bytes: Nullable<i32>,
+    token: Nullable<String>,
+    max_active: i32,
+    max_attempts: i32,
+    cache: Robj,
+    journal: Nullable<String>,
+    row_keys: Robj,
+    arrow: Robj,
+    cassette: Robj,
+    on_layout: Nullable<Function>,
+) -> Robj {
+    let url = match endpoint_url(service_url, "geocodeAddresses") {
+        Some(u) => u,
+        None => throw_r_error(format!("Invalid service url: `{service_url}`")),
+    };
+
+    let fields = InputFields::from_robj(&fields).or_throw();
+    let cols = AddressColumns::from_list(&addresses, &fields, &sr).or_throw();
+    let n = n_rows(&addresses);
+    cols.check_len(n).or_throw();
+    let row_keys = RowKeys::from_robj(row_keys, n).or_throw();
+    let arrow = ArrowOutput::from_robj(&arrow);
+
+    let batch_size = convert::count("batch_size", batch_size).or_throw();
+    let max_bytes = max_bytes
+        .into_option()
+        .map(|m| convert::count("max_bytes", m).or_throw());
+    let max_active = convert::count("max_active", max_active).or_throw();
+    let max_attempts = convert::count("max_attempts", max_attempts).or_throw();
+
+    // parameters that are shared by every request
+    let shared_params = params_form(&params);
+
+    // results can only be persisted when obtained for storage
+    let for_storage = shared_params
+        .iter()
+        .any(|(k, v)| k == "forStorage" && v == "true");
+
+    if matches!(journal, Nullable::NotNull(_)) && !for_storage {
+        throw_r_error("`journal` stores results and requires `forStorage=true`");
+    }
+
+    // a journal is tied to the exact addresses and parameters it was created for
+    let journal = journal.into_option().map(|dir| {
+        let mut fp = Fingerprint::default();
+        fp.update(url.as_str().as_bytes());
+        for (k, v) in shared_params.iter() {
+            fp.update(k.as_bytes());
+            fp.update(v.as_bytes());
+        }
+        match row_keys.as_ref() {
+            // keyed rows are identified by their key rather than their position
+            Some(k) => {
+                let mut order = (0..n).collect::<Vec<_>>();
+                order.sort_by(|&a, &b| k.keys[a].cmp(&k.keys[b]));
+                for i in order {
+                    let rec = serde_json::to_string(&cols.record(i, 0)).unwrap_or_default();
+                    fp.update(k.keys[i].as_bytes());
+                    fp.update(rec.as_bytes());
                 }
-                Err(e) => {
-                    eprintln!("Error occured parsing reponse and anticipated error message.\nInitial error {:?}\nError message: {:?}", ee, e);
+            }
+            None => {
+                for i in 0..n {
+                    let rec =
+                        serde_json::to_string(&cols.record(i, i as i32 + 1)).unwrap_or_default();
+                    fp.update(rec.as_bytes());
                 }
             }
-            ().into_robj()
+        }
+
+        match Journal::open(&dir, n, fp.finish()) {
+            Ok(j) => j,
+            Err(e) => throw_r_error(format!("Failed to open journal: {e}")),
+        }
+    });
+
+    // every response is read into the rows of its ResultIDs
+    let mut results = AlignedResults::new(
+        n,
+        candidate_fields.into_option(),
+        arrow,
+        row_keys.as_ref().map(|k| k.values.clone()),
+        batch_size,
+    );
+
+    // rows from chunks that were completed in a previous call
+    let mut done = vec![false; n];
+
+    if let Some(j) = journal.as_ref() {
+        let entries = match j.completed() {
+            Ok(e) => e,
+            Err(e) => throw_r_error(format!("Failed to read journal: {e}")),
+        };
+
+        // the current row of every key
+        let key_rows = row_keys.as_ref().map(|k| {
+            k.keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| (key.as_str(), i))
+                .collect::<HashMap<_, _>>()
+        });
+
+        for mut entry in entries {
+            let mut rows = match entry.rows(n) {
+                Ok(r) => r,
+                Err(e) => throw_r_error(format!("Failed to read journal: {e}")),
+            };
+
+            // keyed rows are moved to where their key is now
+            if let (Some(key_rows), Some(keys)) = (key_rows.as_ref(), entry.row_keys.as_ref()) {
+                let moved = match keyed_rows(&rows, keys, key_rows) {
+                    Ok(r) => r,
+                    Err(e) => throw_r_error(format!("Failed to read journal: {e}")),
+                };
+                remap_result_ids(&mut entry.response, &rows, &moved);
+                rows = moved;
+            }
+
+            // chunks whose response cannot be read are sent again
+            if results.read(&entry.response.to_string()).is_err() {
+                continue;
+            }
+
+            for row in rows {
+                done[row] = true;
+            }
         }
     }
+
+    // rows that are found in the cache are not requested again
+    let mut cache = GeocodeCache::from_robj(&cache);
+    let url_str = url.to_string();
+    let keys = match cache {
+        Some(_) => (0..n)
+            .map(|i| Some(cache_key(&url_str, &shared_params, &cols.record(i, 0))))
+            .collect::<Vec<_>>(),
+        None => vec![None; n],
+    };
+
+    let mut cached = Vec::new();
+    let mut cached_sr = None;
+    let mut to_send = Vec::with_capacity(n);
+
+    // rows without an address are unmatched and not sent
+    let mut unmatched = vec![false; n];
+    for i in cols.empty_rows(n) {
+        unmatched[i] = true;
+    }
+
+    // duplicated addresses are only sent once. Their results are copied
+    let dupes = Duplicates::new(&cols, n, &unmatched);
+
+    for (i, key) in keys.iter().enumerate() {
+        if done[i] || unmatched[i] || dupes.is_duplicate(i) {
+            continue;
+        }
+
+        let entry = key.as_ref().and_then(|k| cache.as_ref()?.get(k));
+
+        match entry {
+            Some(e) if e.value.is_object() => {
+                // the cached location is placed at this row
+                let mut loc = e.value.clone();
+                if let Some(attrs) = loc.get_mut("attributes").and_then(|a| a.as_object_mut()) {
+                    attrs.insert(String::from("ResultID"), json!(i + 1));
+                }
+                cached.push(loc);
+                if cached_sr.is_none() {
+                    cached_sr = e.sr.clone();
+                }
+            }
+            _ => to_send.push(i),
+        }
+    }
+
+    // chunks are limited by the number of rows and the size of their body
+    let chunks = partition(&cols, &to_send, &shared_params, batch_size, max_bytes);
+
+    if let Nullable::NotNull(f) = on_layout {
+        if let Err(e) = f.call(pairlist!(layout_as_robj(&chunks))) {
+            throw_r_error(format!("Failed to report the layout: {e}"));
+        }
+    }
+
+    let batches = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, c)| Batch {
+            chunk: i as i32 + 1,
+            rows: c.rows.clone(),
+            rejection: None,
+        })
+        .collect::<Vec<_>>();
+
+    let form = |rows: &[usize]| records_form(&cols, rows, &shared_params);
+
+    let transport = Transport::new(url, token.into_option(), max_active)
+        .with_retry(RetryPolicy::new(max_attempts as u32))
+        .with_cassette(Cassette::from_robj(&cassette));
+
+    let mut job = JobResults {
+        results,
+        errors: Vec::new(),
+        error_rows: Vec::new(),
+        replies: Vec::new(),
+        journal,
+        storable: (cache.is_some() && for_storage).then(Vec::new),
+        row_keys: row_keys.as_ref().map(|k| k.keys.clone()),
+    };
+    job.run(&transport, batches, form);
+
+    if let (Some(c), Some(locs)) = (cache.as_mut(), job.storable.take()) {
+        let sr = job.results.spatial_reference();
+        for loc in locs {
+            let key = result_id(&loc).and_then(|id| {
+                let row = usize::try_from(id).ok()?.checked_sub(1)?;
+                keys.get(row)?.clone()
+            });
+
+            if let Some(key) = key {
+                c.insert(key, loc, sr.clone());
+            }
+        }
+
+        if let Err(e) = c.flush() {
+            throw_r_error(format!("Failed to write to geocode cache: {e}"));
+        }
+    }
+
+    let mut results = job.results;
+
+    if !cached.is_empty() {
+        let sr = results.spatial_reference().or(cached_sr);
+        let _ = results.read(&locations_body(sr, cached));
+    }
+
+    let unmatched_rows = (0..n)
+        .filter(|&i| unmatched[i] && !done[i])
+        .map(|i| i as i32 + 1)
+        .collect::<Vec<_>>();
+
+    if !unmatched_rows.is_empty() {
+        let locs = unmatched_rows.iter().map(|&id| unmatched_location(id));
+        let _ = results.read(&locations_body(results.spatial_reference(), locs.collect()));
+    }
+
+    // the results and errors of a distinct row are copied to its duplicates
+    // unless they were completed by a previous call
+    let copies = dupes.copies(&done);
+    for (&first, rows) in copies.iter() {
+        if results.is_filled(first) {
+            for &d in rows {
+                results.copy_row(first, d);
+            }
+        }
+    }
+
+    let fanned_errors = job
+        .error_rows
+        .iter()
+        .flat_map(|e| {
+            let row = e.request.and_then(|id| (id as usize).checked_sub(1));
+            let rows = row
+                .and_then(|r| copies.get(&r))
+                .cloned()
+                .unwrap_or_default();
+            rows.into_iter()
+                .map(move |d| e.clone().with_request(d as i32 + 1))
+        })
+        .collect::<Vec<_>>();
+    job.error_rows.extend(fanned_errors);
+
+    let missing_rows = results.missing();
+    let duplicate_result_ids = std::mem::take(&mut results.duplicates);
+
+    // an Arrow stream without rows still has the schema of the fields
+    let res = match (n, &results.rows) {
+        (0, Rows::Standard(_) | Rows::Custom(_)) => ().into_robj(),
+        _ => results.into_robj(),
+    };
+
+    let attempts = attempts_as_robj(job.replies.iter().map(|(i, r)| (*i, r)));
+
+    // rejected rows are identified by their key as well
+    let mut error_rows = errors_as_robj(&job.error_rows, "object_id");
+    if let Some(k) = row_keys.as_ref() {
+        let ids = job
+            .error_rows
+            .iter()
+            .map(|e| e.request.unwrap_or(0))
+            .collect::<Vec<_>>();
+        error_rows = convert::push_element(error_rows, "row_key", k.subset(&ids));
+    }
+
+    list!(
+        results = res,
+        errors = errors_as_robj(&job.errors, "chunk"),
+        error_rows = error_rows,
+        unmatched_rows = unmatched_rows,
+        skipped_locations = cols.skipped_locations(),
+        missing_rows = missing_rows,
+        duplicate_result_ids = duplicate_result_ids,
+        layout = layout_as_robj(&chunks),
+        n_duplicates = dupes.n_duplicates() as i32,
+        first_rows = dupes
+            .first
+            .iter()
+            .map(|&i| i as i32 + 1)
+            .collect::<Vec<_>>(),
+        attempts = attempts
+    )
+    .into_robj()
 }
 
 extendr_module! {
     mod batch_geocode;
     fn create_records;
+    fn empty_address_rows;
+    fn duplicate_address_rows;
+    fn unmatched_locations_json;
     fn parse_location_json;
+    fn geocode_addresses_rs;
 }
@@ -0,0 +1,664 @@
+// A fake GeocodeServer for testing without a network.
+//
+// Serves the service metadata and the /findAddressCandidates,
+// /geocodeAddresses, /reverseGeocode, and /suggest endpoints.
+// Responses are read from `<fixtures>/<endpoint>.json` when present,
+// otherwise they are generated from the request by deterministic rules.
+// The metadata is served from `<fixtures>/GeocodeServer.json`.
+//
+// Usage:
+//   mock_geocode_server [--port <port>] [--fixtures <dir>] [--fail <rule>]... [--verbose]
+//
+// The url of the service is printed to stdout once it is listening. With
+// `--verbose` every request is logged to stderr.
+//
+// Errors are injected with `--fail <endpoint>:<kind>[:<count>]` where kind
+// is one of `429`, `500`, `502`, `503`, `504` (HTTP status), `error-<code>`
+// (an error body with an HTTP status of 200), or `truncated` (a cut off
+// body). The rule applies to the next `count` requests, by default 1, or
+// every request when `count` is `*`.
+//
+// Records of /geocodeAddresses that contain `REJECT` make the service
+// reject the whole batch with a 400 error and records that contain
+// `UNMATCHED` are returned without a location.
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+const SERVICE_PATH: &str = "/arcgis/rest/services/Mock/GeocodeServer";
+const ENDPOINTS: [&str; 4] = [
+    "findAddressCandidates",
+    "geocodeAddresses",
+    "reverseGeocode",
+    "suggest",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum FailKind {
+    Status(u16),
+    ErrorBody(i32),
+    Truncated,
+}
+
+#[derive(Debug, Clone)]
+struct FailRule {
+    endpoint: String,
+    kind: FailKind,
+    // None fails every request
+    remaining: Option<u32>,
+}
+
+impl FailRule {
+    fn parse(x: &str) -> Option<Self> {
+        let mut parts = x.split(':');
+        let endpoint = parts.next()?.to_string();
+        let kind = match parts.next()? {
+            "truncated" => FailKind::Truncated,
+            k if k.starts_with("error-") => FailKind::ErrorBody(k[6..].parse().ok()?),
+            k => FailKind::Status(k.parse().ok()?),
+        };
+        let remaining = match parts.next() {
+            None => Some(1),
+            Some("*") => None,
+            Some(n) => Some(n.parse().ok()?),
+        };
+        Some(FailRule {
+            endpoint,
+            kind,
+            remaining,
+        })
+    }
+}
+
+struct Server {
+    fixtures: Option<PathBuf>,
+    rules: Mutex<Vec<FailRule>>,
+    // log every request to stderr
+    verbose: bool,
+}
+
+struct Response {
+    status: u16,
+    body: String,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Response {
+            status: 200,
+            body: body.to_string(),
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
+
+fn error_body(code: i32, message: &str) -> Value {
+    json!({
+        "error": {
+            "code": code,
+            "extendedCode": code,
+            "message": message,
+            "details": []
+        }
+    })
+}
+
+// Decodes an application/x-www-form-urlencoded string
+fn decode_component(x: &str) -> String {
+    let bytes = x.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                // the escape is read from the bytes since it may be followed
+                // by a multi-byte character
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn parse_form(x: &str, params: &mut HashMap<String, String>) {
+    for pair in x.split('&').filter(|p| !p.is_empty()) {
+        let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+        params.insert(decode_component(k), decode_component(v));
+    }
+}
+
+// Hashes text to a stable coordinate so that results are reproducible
+fn hash_point(text: &str) -> (f64, f64) {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in text.as_bytes() {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    let x = (h % 36_000) as f64 / 100.0 - 180.0;
+    let y = ((h >> 32) % 18_000) as f64 / 100.0 - 90.0;
+    (x, y)
+}
+
+fn spatial_reference() -> Value {
+    json!({ "wkid": 4326, "latestWkid": 4326 })
+}
+
+fn field(name: &str, alias: &str, length: i32) -> Value {
+    json!({
+        "name": name,
+        "type": "esriFieldTypeString",
+        "alias": alias,
+        "required": false,
+        "length": length
+    })
+}
+
+fn metadata() -> Value {
+    let address_fields = [
+        ("Address", "Address or Place", 100),
+        ("Address2", "Address2", 100),
+        ("Address3", "Address3", 100),
+        ("Neighborhood", "Neighborhood", 50),
+        ("City", "City", 50),
+        ("Subregion", "County", 50),
+        ("Region", "State", 50),
+        ("Postal", "ZIP", 20),
+        ("PostalExt", "ZIP4", 20),
+        ("CountryCode", "Country", 100),
+    ]
+    .iter()
+    .map(|(n, a, l)| field(n, a, *l))
+    .collect::<Vec<_>>();
+
+    let candidate_fields = [
+        ("ResultID", "esriFieldTypeInteger"),
+        ("Loc_name", "esriFieldTypeString"),
+        ("Status", "esriFieldTypeString"),
+        ("Score", "esriFieldTypeDouble"),
+        ("Match_addr", "esriFieldTypeString"),
+        ("LongLabel", "esriFieldTypeString"),
+        ("ShortLabel", "esriFieldTypeString"),
+        ("Addr_type", "esriFieldTypeString"),
+        ("City", "esriFieldTypeString"),
+        ("Region", "esriFieldTypeString"),
+        ("Postal", "esriFieldTypeString"),
+        ("CntryName", "esriFieldTypeString"),
+        ("X", "esriFieldTypeDouble"),
+        ("Y", "esriFieldTypeDouble"),
+    ]
+    .iter()
+    .map(|(n, t)| json!({ "name": n, "type": t, "alias": n, "required": false }))
+    .collect::<Vec<_>>();
+
+    json!({
+        "currentVersion": 11.3,
+        "serviceDescription": "Mock GeocodeServer",
+        "addressFields": address_fields,
+        "categories": [],
+        "singleLineAddressField": field("SingleLine", "Single Line Input", 200),
+        "candidateFields": candidate_fields,
+        "spatialReference": spatial_reference(),
+        "locatorProperties": {
+            "UICLIDs": "",
+            "MaxBatchSize": 1000,
+            "SuggestedBatchSize": 150,
+            "LoadBalancerTimeOut": 60,
+            "isAGOWorldLocator": false,
+            "WriteXYCoordFields": "TRUE",
+            "MaxResultSize": 50
+        },
+        "capabilities": "Geocode,ReverseGeocode,Suggest"
+    })
+}
+
+fn attributes(result_id: Option<i64>, text: &str, matched: bool, x: f64, y: f64) -> Value {
+    let mut attrs = Map::new();
+    if let Some(id) = result_id {
+        attrs.insert("ResultID".into(), json!(id));
+    }
+    let (status, score, label) = if matched {
+        ("M", 100.0, text)
+    } else {
+        ("U", 0.0, "")
+    };
+    attrs.insert("Loc_name".into(), json!("Mock"));
+    attrs.insert("Status".into(), json!(status));
+    attrs.insert("Score".into(), json!(score));
+    attrs.insert("Match_addr".into(), json!(label));
+    attrs.insert("LongLabel".into(), json!(label));
+    attrs.insert("ShortLabel".into(), json!(label));
+    attrs.insert(
+        "Addr_type".into(),
+        json!(if matched { "PointAddress" } else { "" }),
+    );
+    attrs.insert("City".into(), json!(""));
+    attrs.insert("Region".into(), json!(""));
+    attrs.insert("Postal".into(), json!(""));
+    attrs.insert("CntryName".into(), json!(""));
+    attrs.insert("X".into(), json!(x));
+    attrs.insert("Y".into(), json!(y));
+    Value::Object(attrs)
+}
+
+// Combines the address fields of a request into a single line
+fn address_text(x: &Map<String, Value>) -> String {
+    let keys = [
+        "SingleLine",
+        "singleLine",
+        "Address",
+        "address",
+        "Address2",
+        "address2",
+        "Address3",
+        "address3",
+        "Neighborhood",
+        "neighborhood",
+        "City",
+        "city",
+        "Subregion",
+        "subregion",
+        "Region",
+        "region",
+        "Postal",
+        "postal",
+        "PostalExt",
+        "postalExt",
+        "CountryCode",
+        "countryCode",
+    ];
+
+    keys.iter()
+        .filter_map(|k| x.get(*k).and_then(|v| v.as_str()))
+        .map(|v| v.trim())
+        .filter(|v| !v.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn find_address_candidates(params: &HashMap<String, String>) -> Response {
+    let fields = params
+        .iter()
+        .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+        .collect::<Map<_, _>>();
+
+    let text = address_text(&fields);
+    let max_locations = params
+        .get("maxLocations")
+        .and_then(|m| m.parse::<usize>().ok())
+        .unwrap_or(1);
+
+    let candidates = if text.is_empty() {
+        vec![]
+    } else {
+        (0..max_locations.clamp(1, 2))
+            .map(|i| {
+                let (x, y) = hash_point(&format!("{text}{i}"));
+                json!({
+                    "address": text,
+                    "location": { "x": x, "y": y },
+                    "score": 100.0 - i as f64,
+                    "attributes": attributes(None, &text, true, x, y),
+                    "extent": {
+                        "xmin": x - 0.001,
+                        "ymin": y - 0.001,
+                        "xmax": x + 0.001,
+                        "ymax": y + 0.001
+                    }
+                })
+            })
+            .collect()
+    };
+
+    Response::ok(json!({
+        "spatialReference": spatial_reference(),
+        "candidates": candidates
+    }))
+}
+
+fn geocode_addresses(params: &HashMap<String, String>) -> Response {
+    let addresses = params
+        .get("addresses")
+        .and_then(|a| serde_json::from_str::<Value>(a).ok());
+
+    let records = match addresses
+        .as_ref()
+        .and_then(|a| a.get("records"))
+        .and_then(|r| r.as_array())
+    {
+        Some(r) => r,
+        None => {
+            return Response::ok(error_body(400, "Unable to complete operation."));
+        }
+    };
+
+    let mut locations = Vec::with_capacity(records.len());
+
+    for rec in records {
+        let attrs = match rec.get("attributes").and_then(|a| a.as_object()) {
+            Some(a) => a,
+            None => return Response::ok(error_body(400, "Invalid record.")),
+        };
+
+        let text = address_text(attrs);
+        if text.contains("REJECT") {
+            return Response::ok(error_body(400, "Invalid address in batch."));
+        }
+
+        let oid = attrs
+            .get("OBJECTID")
+            .or_else(|| attrs.get("objectid"))
+            .and_then(|o| o.as_i64());
+
+        let matched = !text.is_empty() && !text.contains("UNMATCHED");
+        let (x, y) = hash_point(&text);
+
+        let mut loc = json!({
+            "address": if matched { text.as_str() } else { "" },
+            "score": if matched { 100.0 } else { 0.0 },
+            "attributes": attributes(oid, &text, matched, x, y)
+        });
+
+        if matched {
+            loc["location"] = json!({ "x": x, "y": y });
+        }
+
+        locations.push(loc);
+    }
+
+    // the service does not return the locations in the order of the records
+    locations.reverse();
+
+    Response::ok(json!({
+        "spatialReference": spatial_reference(),
+        "locations": locations
+    }))
+}
+
+fn reverse_geocode(params: &HashMap<String, String>) -> Response {
+    let location = params.get("location").map(|l| l.trim()).unwrap_or("");
+
+    // the location is either `x,y` or an esri point
+    let point = match serde_json::from_str::<Value>(location) {
+        Ok(v) => v
+            .get("x")
+            .and_then(|x| x.as_f64())
+            .zip(v.get("y").and_then(|y| y.as_f64())),
+        Err(_) => location.split_once(',').and_then(|(x, y)| {
+            x.trim()
+                .parse::<f64>()
+                .ok()
+                .zip(y.trim().parse::<f64>().ok())
+        }),
+    };
+
+    let (x, y) = match point {
+        Some(p) => p,
+        None => {
+            return Response::ok(error_body(400, "Unable to complete operation."));
+        }
+    };
+
+    let label = format!("{x:.4}, {y:.4}");
+    let mut address = Map::new();
+    for key in ["Match_addr", "LongLabel", "ShortLabel", "Address"] {
+        address.insert(key.into(), json!(label));
+    }
+    address.insert("Addr_type".into(), json!("PointAddress"));
+    address.insert("Type".into(), json!(""));
+    for key in [
+        "PlaceName",
+        "AddNum",
+        "Block",
+        "Sector",
+        "Neighborhood",
+        "District",
+        "City",
+        "MetroArea",
+        "Subregion",
+        "Region",
+        "RegionAbbr",
+        "Territory",
+        "Postal",
+        "PostalExt",
+        "CntryName",
+        "CountryCode",
+    ] {
+        address.insert(key.into(), json!(""));
+    }
+
+    Response::ok(json!({
+        "address": address,
+        "location": { "x": x, "y": y, "spatialReference": spatial_reference() }
+    }))
+}
+
+fn suggest(params: &HashMap<String, String>) -> Response {
+    let text = params.get("text").map(|t| t.trim()).unwrap_or("");
+    let max_suggestions = params
+        .get("maxSuggestions")
+        .and_then(|m| m.parse::<usize>().ok())
+        .unwrap_or(5);
+
+    let suggestions = if text.is_empty() {
+        vec![]
+    } else {
+        (0..max_suggestions.min(3))
+            .map(|i| {
+                let (x, y) = hash_point(&format!("{text}{i}"));
+                json!({
+                    "text": format!("{text} {}", i + 1),
+                    "magicKey": format!("mock-{x:.4}-{y:.4}"),
+                    "isCollection": false
+                })
+            })
+            .collect()
+    };
+
+    Response::ok(json!({ "suggestions": suggestions }))
+}
+
+impl Server {
+    // Takes the next failure for an endpoint if there is one
+    fn next_failure(&self, endpoint: &str) -> Option<FailKind> {
+        let mut rules = self.rules.lock().unwrap_or_else(|e| e.into_inner());
+        let rule = rules
+            .iter_mut()
+            .find(|r| r.endpoint == endpoint && r.remaining != Some(0))?;
+
+        if let Some(n) = rule.remaining.as_mut() {
+            *n -= 1;
+        }
+        Some(rule.kind.clone())
+    }
+
+    fn fixture(&self, endpoint: &str) -> Option<String> {
+        let path = self.fixtures.as_ref()?.join(format!("{endpoint}.json"));
+        std::fs::read_to_string(path).ok()
+    }
+
+    fn respond(&self, path: &str, params: &HashMap<String, String>) -> Response {
+        let path = path.trim_end_matches('/');
+
+        let endpoint = if path == SERVICE_PATH {
+            "GeocodeServer"
+        } else {
+            match path
+                .strip_prefix(SERVICE_PATH)
+                .and_then(|p| p.strip_prefix('/'))
+                .filter(|p| ENDPOINTS.contains(p))
+            {
+                Some(e) => e,
+                None => {
+                    return Response {
+                        status: 404,
+                        body: error_body(404, "Not Found").to_string(),
+                    }
+                }
+            }
+        };
+
+        let failure = self.next_failure(endpoint);
+        if let Some(FailKind::Status(status)) = failure {
+            return Response {
+                status,
+                body: error_body(status as i32, reason(status)).to_string(),
+            };
+        }
+        if let Some(FailKind::ErrorBody(code)) = failure {
+            return Response::ok(error_body(code, "Injected error."));
+        }
+
+        let mut resp = match self.fixture(endpoint) {
+            Some(body) => Response { status: 200, body },
+            None => match endpoint {
+                "GeocodeServer" => Response::ok(metadata()),
+                "findAddressCandidates" => find_address_candidates(params),
+                "geocodeAddresses" => geocode_addresses(params),
+                "reverseGeocode" => reverse_geocode(params),
+                _ => suggest(params),
+            },
+        };
+
+        if failure == Some(FailKind::Truncated) {
+            let mut cut = resp.body.len() / 2;
+            while !resp.body.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            resp.body.truncate(cut);
+        }
+
+        resp
+    }
+
+    fn handle(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((k, v)) = line.split_once(':') {
+                if k.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = v.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let mut params = HashMap::new();
+        parse_form(query, &mut params);
+        parse_form(&String::from_utf8_lossy(&body), &mut params);
+
+        let resp = self.respond(path, &params);
+        if self.verbose {
+            eprintln!(
+                "{} {path} -> {}",
+                request_line.split(' ').next().unwrap_or(""),
+                resp.status
+            );
+        }
+
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nRetry-After: 0\r\nConnection: close\r\n\r\n",
+            resp.status,
+            reason(resp.status),
+            resp.body.len()
+        )?;
+        stream.write_all(resp.body.as_bytes())?;
+        stream.flush()
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: mock_geocode_server [--port <port>] [--fixtures <dir>] [--fail <endpoint>:<kind>[:<count>]]... [--verbose]"
+    );
+    std::process::exit(2)
+}
+
+fn main() {
+    let mut port = 0;
+    let mut fixtures = None;
+    let mut rules = Vec::new();
+    let mut verbose = false;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--verbose" {
+            verbose = true;
+            continue;
+        }
+
+        let val = args.next().unwrap_or_else(|| usage());
+        match arg.as_str() {
+            "--port" => port = val.parse().unwrap_or_else(|_| usage()),
+            "--fixtures" => fixtures = Some(PathBuf::from(val)),
+            "--fail" => rules.push(FailRule::parse(&val).unwrap_or_else(|| usage())),
+            _ => usage(),
+        }
+    }
+
+    let listener = match TcpListener::bind(("127.0.0.1", port)) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("Failed to bind to port {port}: {e}");
+            std::process::exit(1)
+        }
+    };
+
+    let addr = listener.local_addr().expect("listener has an address");
+    println!("http://{addr}{SERVICE_PATH}");
+    let _ = std::io::stdout().flush();
+
+    let server = Arc::new(Server {
+        fixtures,
+        rules: Mutex::new(rules),
+        verbose,
+    });
+
+    for stream in listener.incoming().flatten() {
+        let server = server.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = server.handle(stream) {
+                eprintln!("Failed to handle request: {e}");
+            }
+        });
+    }
+}
@@ -0,0 +1,460 @@
+use crate::transport::endpoint_url;
+use extendr_api::{deserializer::from_robj, prelude::*};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use unicode_normalization::UnicodeNormalization;
+
+// How the cache is configured from R.
+// `ttl` is the number of seconds an entry is valid for
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheOptions {
+    pub path: String,
+    pub ttl: Option<f64>,
+}
+
+// A single line of the cache file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub key: String,
+    pub created: u64,
+    pub expires: Option<u64>,
+    pub value: Value,
+    // the spatial reference of the geometry in `value`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sr: Option<Value>,
+}
+
+// The part of a line that is needed to index it. The value is skipped
+// without being allocated.
+#[derive(Debug, Deserialize)]
+struct EntryHeader {
+    key: String,
+    expires: Option<u64>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Where the latest line of a key is in the cache file
+#[derive(Debug, Clone, Copy)]
+struct Slot {
+    offset: u64,
+    len: usize,
+    expires: Option<u64>,
+}
+
+impl Slot {
+    fn is_expired(&self, ts: u64) -> bool {
+        self.expires.map_or(false, |e| e <= ts)
+    }
+}
+
+// The position of every key in a cache file. Only the keys are kept in
+// memory and values are read from the file when they are requested.
+#[derive(Debug, Default)]
+struct Index {
+    slots: HashMap<String, Slot>,
+    // the number of bytes and lines of the file that have been indexed
+    indexed: u64,
+    n_lines: usize,
+}
+
+impl Index {
+    // Indexes the lines that were appended to the file since it was last
+    // read. A file that shrank was rewritten and is indexed from the start.
+    // A last line without a newline is still being written and is skipped.
+    fn refresh(&mut self, file: &File) -> std::io::Result<()> {
+        let len = file.metadata()?.len();
+        if len < self.indexed {
+            *self = Index::default();
+        }
+        if len == self.indexed {
+            return Ok(());
+        }
+
+        let mut reader = BufReader::new(file);
+        reader.seek(SeekFrom::Start(self.indexed))?;
+
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+
+            // lines that cannot be parsed are skipped and later entries
+            // replace earlier ones
+            if let Ok(h) = serde_json::from_slice::<EntryHeader>(&line) {
+                let slot = Slot {
+                    offset: self.indexed,
+                    len: n,
+                    expires: h.expires,
+                };
+                self.slots.insert(h.key, slot);
+            }
+            self.indexed += n as u64;
+            self.n_lines += 1;
+        }
+
+        Ok(())
+    }
+
+    // Whether most lines of the file are replaced or expired entries
+    fn is_stale(&self, ts: u64) -> bool {
+        let live = self.slots.values().filter(|s| !s.is_expired(ts)).count();
+        self.n_lines > 1024 && self.n_lines > 2 * live
+    }
+}
+
+// The indexes of the cache files that were opened in this session. A cache
+// takes the index of its file when it is opened and returns it when it is
+// dropped so that a file is only read in full once per session.
+static INDEXES: Mutex<Option<HashMap<PathBuf, Index>>> = Mutex::new(None);
+
+fn take_index(path: &PathBuf) -> Index {
+    let mut indexes = INDEXES.lock().unwrap_or_else(|e| e.into_inner());
+    indexes
+        .as_mut()
+        .and_then(|i| i.remove(path))
+        .unwrap_or_default()
+}
+
+fn return_index(path: PathBuf, index: Index) {
+    let mut indexes = INDEXES.lock().unwrap_or_else(|e| e.into_inner());
+    indexes.get_or_insert_with(HashMap::new).insert(path, index);
+}
+
+// A geocoding cache that is stored as a single file of JSON lines.
+// The file is indexed by key when the cache is opened and values are read
+// when they are requested. New entries are only written to the file when
+// the cache is flushed. When most of the file is made up of replaced or
+// expired entries it is rewritten with only the valid entries.
+#[derive(Debug)]
+pub struct GeocodeCache {
+    path: PathBuf,
+    ttl: Option<u64>,
+    file: Option<File>,
+    index: Index,
+    pending: Vec<CacheEntry>,
+    // the position of each key in `pending`
+    pending_keys: HashMap<String, usize>,
+}
+
+impl GeocodeCache {
+    pub fn open(opts: CacheOptions) -> std::io::Result<Self> {
+        let path = PathBuf::from(opts.path);
+        let ttl = opts.ttl.filter(|t| *t > 0.0).map(|t| t as u64);
+        let mut index = take_index(&path);
+
+        let file = match File::open(&path) {
+            Ok(f) => {
+                index.refresh(&f)?;
+                Some(f)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                index = Index::default();
+                None
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(GeocodeCache {
+            path,
+            ttl,
+            file,
+            index,
+            pending: Vec::new(),
+            pending_keys: HashMap::new(),
+        })
+    }
+
+    // Opens a cache from its R representation. NULL disables the cache
+    pub fn from_robj(x: &Robj) -> Option<Self> {
+        if x.is_null() {
+            return None;
+        }
+
+        let opts = match from_robj::<CacheOptions>(x) {
+            Ok(o) => o,
+            Err(e) => throw_r_error(format!("Invalid cache options: {e}")),
+        };
+
+        match GeocodeCache::open(opts) {
+            Ok(c) => Some(c),
+            Err(e) => throw_r_error(format!("Failed to open geocode cache: {e}")),
+        }
+    }
+
+    // Reads the entry of a slot from the cache file
+    fn read(&self, slot: &Slot) -> Option<CacheEntry> {
+        let mut file = self.file.as_ref()?;
+        file.seek(SeekFrom::Start(slot.offset)).ok()?;
+        let mut line = vec![0; slot.len];
+        file.read_exact(&mut line).ok()?;
+        serde_json::from_slice(&line).ok()
+    }
+
+    // Returns the entry of a key unless it is missing or expired
+    pub fn get(&self, key: &str) -> Option<CacheEntry> {
+        if let Some(&i) = self.pending_keys.get(key) {
+            return Some(self.pending[i].clone());
+        }
+
+        let slot = self.index.slots.get(key)?;
+        if slot.is_expired(now()) {
+            return None;
+        }
+
+        // a file that was rewritten by another session no longer matches
+        // the index
+        self.read(slot).filter(|e| e.key == key)
+    }
+
+    pub fn insert(&mut self, key: String, value: Value, sr: Option<Value>) {
+        let created = now();
+        let entry = CacheEntry {
+            key: key.clone(),
+            created,
+            expires: self.ttl.map(|t| created + t),
+            value,
+            sr,
+        };
+
+        match self.pending_keys.get(&key) {
+            Some(&i) => self.pending[i] = entry,
+            None => {
+                self.pending_keys.insert(key, self.pending.len());
+                self.pending.push(entry);
+            }
+        }
+    }
+
+    // Appends new entries to the cache file and indexes them
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&self.path)?;
+
+        // lines appended by another session are indexed first so that the
+        // offsets of the new lines are known
+        self.index.refresh(&file)?;
+
+        let mut writer = BufWriter::new(&file);
+        let mut line = Vec::new();
+        for entry in self.pending.drain(..) {
+            line.clear();
+            serde_json::to_writer(&mut line, &entry)?;
+            line.push(b'\n');
+            writer.write_all(&line)?;
+
+            let slot = Slot {
+                offset: self.index.indexed,
+                len: line.len(),
+                expires: entry.expires,
+            };
+            self.index.slots.insert(entry.key, slot);
+            self.index.indexed += line.len() as u64;
+            self.index.n_lines += 1;
+        }
+        writer.flush()?;
+        drop(writer);
+        self.pending_keys.clear();
+        self.file = Some(file);
+
+        if self.index.is_stale(now()) {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    // Rewrites the cache file with only the entries that are still valid
+    fn compact(&mut self) -> std::io::Result<()> {
+        let ts = now();
+        let tmp = self.path.with_extension("tmp");
+        let mut index = Index::default();
+
+        {
+            let mut writer = BufWriter::new(File::create(&tmp)?);
+            let mut slots = self
+                .index
+                .slots
+                .iter()
+                .filter(|(_, s)| !s.is_expired(ts))
+                .collect::<Vec<_>>();
+            // entries are kept in the order that they were written
+            slots.sort_by_key(|(_, s)| s.offset);
+
+            let mut line = Vec::new();
+            for (key, slot) in slots {
+                let entry = match self.read(slot) {
+                    Some(e) => e,
+                    None => continue,
+                };
+                line.clear();
+                serde_json::to_writer(&mut line, &entry)?;
+                line.push(b'\n');
+                writer.write_all(&line)?;
+
+                let new_slot = Slot {
+                    offset: index.indexed,
+                    len: line.len(),
+                    expires: slot.expires,
+                };
+                index.slots.insert(key.clone(), new_slot);
+                index.indexed += line.len() as u64;
+                index.n_lines += 1;
+            }
+            writer.flush()?;
+        }
+
+        // the file is closed first since open files cannot be replaced on
+        // every platform
+        self.file = None;
+        fs::rename(&tmp, &self.path)?;
+        self.file = Some(File::open(&self.path)?);
+        self.index = index;
+        Ok(())
+    }
+}
+
+impl Drop for GeocodeCache {
+    fn drop(&mut self) {
+        let index = std::mem::take(&mut self.index);
+        return_index(self.path.clone(), index);
+    }
+}
+
+// Normalizes a request so that equivalent requests share a key.
+// Strings are NFKC normalized, trimmed, lower cased, and have repeated
+// whitespace removed. Missing and empty values are dropped.
+fn normalize_value(x: Value) -> Value {
+    match x {
+        Value::String(s) => {
+            let s = s.nfkc().collect::<String>();
+            let s = s.split_whitespace().collect::<Vec<_>>().join(" ");
+            Value::String(s.to_lowercase())
+        }
+        Value::Object(obj) => obj
+            .into_iter()
+            .map(|(k, v)| (k, normalize_value(v)))
+            .filter(|(_, v)| !(v.is_null() || v.as_str() == Some("")))
+            .collect(),
+        Value::Array(arr) => arr.into_iter().map(normalize_value).collect(),
+        v => v,
+    }
+}
+
+// Creates a cache key from the url of an endpoint, the parameters shared
+// by every request, and the request itself. Fields that do not change the
+// result of a request such as `objectid` are omitted. `forStorage` is kept
+// so that results obtained for storage are never served to other requests.
+pub fn cache_key<T: Serialize>(url: &str, params: &[(String, String)], request: &T) -> String {
+    let mut req = serde_json::to_value(request).unwrap_or(Value::Null);
+
+    if let Some(obj) = req.as_object_mut() {
+        obj.remove("objectid");
+    }
+
+    let params = params
+        .iter()
+        .filter(|(k, _)| k != "f")
+        .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+        .collect::<serde_json::Map<_, _>>();
+
+    format!(
+        "{url}\n{}\n{}",
+        normalize_value(Value::Object(params)),
+        normalize_value(req)
+    )
+}
+
+// Creates cache keys for /findAddressCandidates requests.
+// `params` are the JSON encoded parameters of each request
+#[extendr]
+fn candidate_cache_keys(service_url: &str, params: Strings) -> Strings {
+    let url = match endpoint_url(service_url, "findAddressCandidates") {
+        Some(u) => u.to_string(),
+        None => throw_r_error(format!("Invalid service url: `{service_url}`")),
+    };
+
+    params
+        .iter()
+        .map(|p| {
+            let req = serde_json::from_str::<Value>(p.as_str()).unwrap_or(Value::Null);
+            cache_key(&url, &[], &req)
+        })
+        .collect::<Strings>()
+}
+
+// Returns the cached response for each key or NA when missing
+#[extendr]
+fn candidate_cache_get(cache: Robj, keys: Strings) -> Strings {
+    let cache = match GeocodeCache::from_robj(&cache) {
+        Some(c) => c,
+        None => return keys.iter().map(|_| Rstr::na()).collect::<Strings>(),
+    };
+
+    // responses are stored as their raw body
+    keys.iter()
+        .map(|k| match cache.get(k.as_str()).map(|e| e.value) {
+            Some(Value::String(body)) => Rstr::from(body.as_str()),
+            Some(value) => Rstr::from(value.to_string()),
+            None => Rstr::na(),
+        })
+        .collect::<Strings>()
+}
+
+// Stores the body of /findAddressCandidates responses that contain
+// candidates. The body is stored as is so that the custom fields of a
+// locator are kept. This must only be called when the results were
+// obtained with `forStorage`
+#[extendr]
+fn candidate_cache_put(cache: Robj, keys: Strings, bodies: Strings) {
+    let mut cache = match GeocodeCache::from_robj(&cache) {
+        Some(c) => c,
+        None => return,
+    };
+
+    for (key, body) in keys.iter().zip(bodies.iter()) {
+        if key.is_na() || body.is_na() {
+            continue;
+        }
+
+        // error bodies are never cached
+        let has_candidates = serde_json::from_str::<Value>(body.as_str()).map_or(false, |v| {
+            v.get("candidates").map_or(false, Value::is_array)
+        });
+
+        if has_candidates {
+            cache.insert(key.to_string(), Value::String(body.to_string()), None);
+        }
+    }
+
+    if let Err(e) = cache.flush() {
+        throw_r_error(format!("Failed to write to geocode cache: {e}"));
+    }
+}
+
+extendr_module! {
+    mod cache;
+    fn candidate_cache_keys;
+    fn candidate_cache_get;
+    fn candidate_cache_put;
+}
@@ -0,0 +1,196 @@
+use crate::transport::FormBody;
+use extendr_api::{deserializer::from_robj, prelude::*};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const REDACTED: &str = "<redacted>";
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CassetteMode {
+    // requests are sent and every response is written to the cassette
+    Record,
+    // responses are served from the cassette and nothing is sent
+    Replay,
+}
+
+// How the cassette is configured from R
+#[derive(Debug, Clone, Deserialize)]
+pub struct CassetteOptions {
+    pub path: String,
+    pub mode: CassetteMode,
+}
+
+// A single request and its response. This is a line of the cassette file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    pub url: String,
+    pub form: FormBody,
+    pub status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+    pub body: String,
+}
+
+// Recorded traffic stored as a file of JSON lines.
+// In record mode interactions are appended to the file when flushed.
+// In replay mode each interaction is served once in the order that it was
+// recorded. When every matching interaction has been served, the last one
+// is served again.
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    pub mode: CassetteMode,
+    interactions: Vec<Interaction>,
+    served: Mutex<Vec<bool>>,
+    pending: Mutex<Vec<Interaction>>,
+}
+
+// Removes the token from a request or response so that it is not stored
+fn redact(x: &str, token: Option<&str>) -> String {
+    match token.filter(|t| !t.is_empty()) {
+        Some(t) => x.replace(t, REDACTED),
+        None => x.to_string(),
+    }
+}
+
+fn redact_form(form: &FormBody, token: Option<&str>) -> FormBody {
+    form.iter()
+        .map(|(k, v)| {
+            if k == "token" {
+                (k.clone(), REDACTED.to_string())
+            } else {
+                (k.clone(), redact(v, token))
+            }
+        })
+        .collect()
+}
+
+impl Cassette {
+    pub fn open(opts: CassetteOptions) -> std::io::Result<Self> {
+        let path = PathBuf::from(opts.path);
+        let mut interactions = Vec::new();
+
+        if opts.mode == CassetteMode::Replay {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                interactions.push(serde_json::from_str::<Interaction>(&line)?);
+            }
+        }
+
+        Ok(Cassette {
+            path,
+            mode: opts.mode,
+            served: Mutex::new(vec![false; interactions.len()]),
+            interactions,
+            pending: Mutex::new(Vec::new()),
+        })
+    }
+
+    // Opens a cassette from its R representation. NULL disables it
+    pub fn from_robj(x: &Robj) -> Option<Self> {
+        if x.is_null() {
+            return None;
+        }
+
+        let opts = match from_robj::<CassetteOptions>(x) {
+            Ok(o) => o,
+            Err(e) => throw_r_error(format!("Invalid cassette options: {e}")),
+        };
+
+        match Cassette::open(opts) {
+            Ok(c) => Some(c),
+            Err(e) => throw_r_error(format!("Failed to open cassette: {e}")),
+        }
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.mode == CassetteMode::Replay
+    }
+
+    // Stores a response to a request
+    pub fn record(
+        &self,
+        url: &str,
+        form: &FormBody,
+        token: Option<&str>,
+        status: u16,
+        retry_after: Option<Duration>,
+        body: &str,
+    ) {
+        let interaction = Interaction {
+            url: redact(url, token),
+            form: redact_form(form, token),
+            status,
+            retry_after: retry_after.map(|d| d.as_secs()),
+            body: redact(body, token),
+        };
+
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(interaction);
+    }
+
+    // Finds the recorded response to a request
+    pub fn replay(
+        &self,
+        url: &str,
+        form: &FormBody,
+        token: Option<&str>,
+    ) -> Option<(u16, Option<Duration>, String)> {
+        let url = redact(url, token);
+        let form = redact_form(form, token);
+        let mut served = self.served.lock().unwrap_or_else(|e| e.into_inner());
+
+        let matching = self
+            .interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| x.url == url && x.form == form)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        let i = matching
+            .iter()
+            .find(|i| !served[**i])
+            .or(matching.last())
+            .copied()?;
+
+        served[i] = true;
+        let x = &self.interactions[i];
+        Some((
+            x.status,
+            x.retry_after.map(Duration::from_secs),
+            x.body.clone(),
+        ))
+    }
+
+    // Appends recorded interactions to the cassette file
+    pub fn flush(&self) -> std::io::Result<()> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        let mut writer = BufWriter::new(file);
+        for x in pending.drain(..) {
+            serde_json::to_writer(&mut writer, &x)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+}
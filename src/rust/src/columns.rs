@@ -0,0 +1,1153 @@
+use crate::convert;
+use crate::find_candidates::Extent;
+use crate::schema::{self, FieldType, Problem};
+use extendr_api::prelude::*;
+use serde::de::value::{MapAccessDeserializer, SeqAccessDeserializer};
+use serde::de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_esri::spatial_reference::SpatialReference;
+use serde_json::Value;
+use std::borrow::Cow;
+use std::fmt;
+
+// Parses the results of /findAddressCandidates and /geocodeAddresses
+// directly into R vectors or Arrow buffers. The results are scanned for
+// their count and ResultIDs first so that each column is allocated once and
+// every value is written into its row as it is read instead of collecting a
+// struct per result.
+
+// The R type of an attribute column. Logical columns are only inferred for
+// attributes that are not one of the fields.
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Lgl,
+    Int,
+    Dbl,
+    Str,
+}
+
+impl Kind {
+    // The type of a field of the `candidateFields` of a locator. Dates are
+    // the milliseconds since the epoch.
+    pub fn of(x: FieldType) -> Self {
+        match x {
+            FieldType::SmallInteger | FieldType::Integer | FieldType::Oid => Kind::Int,
+            FieldType::BigInteger | FieldType::Single | FieldType::Double | FieldType::Date => {
+                Kind::Dbl
+            }
+            _ => Kind::Str,
+        }
+    }
+
+    // The type of an attribute that is not one of the fields
+    fn infer(x: &Value) -> Option<Self> {
+        match x {
+            Value::Null => None,
+            Value::String(s) if s.trim().is_empty() => None,
+            Value::Bool(_) => Some(Kind::Lgl),
+            Value::Number(_) => Some(Kind::Dbl),
+            _ => Some(Kind::Str),
+        }
+    }
+}
+
+// An attribute of the results. `name` is its name in the response and
+// `column` is its name in the data.frame. The fields of the World Geocoder
+// are static while those of other locators come from their metadata.
+#[derive(Debug, Clone)]
+pub struct AttributeField {
+    pub name: Cow<'static, str>,
+    pub column: Cow<'static, str>,
+    pub kind: Kind,
+}
+
+impl AttributeField {
+    pub const fn new(name: &'static str, column: &'static str, kind: Kind) -> Self {
+        AttributeField {
+            name: Cow::Borrowed(name),
+            column: Cow::Borrowed(column),
+            kind,
+        }
+    }
+
+    // A field of a locator whose column is named like the field
+    pub fn named(name: String, kind: Kind) -> Self {
+        AttributeField {
+            name: Cow::Owned(name.clone()),
+            column: Cow::Owned(name),
+            kind,
+        }
+    }
+}
+
+// A vector that the values of an attribute are written into. Values are
+// only set when they match the kind of the column.
+pub trait ColumnStore: Sized {
+    // A column of `n` missing values
+    fn new(kind: Kind, n: usize) -> Self;
+    fn kind(&self) -> Kind;
+    fn set_lgl(&mut self, row: usize, x: bool);
+    fn set_int(&mut self, row: usize, x: i32);
+    fn set_dbl(&mut self, row: usize, x: f64);
+    fn set_str(&mut self, row: usize, x: &str);
+    fn clear(&mut self, row: usize);
+    fn copy(&mut self, from: usize, to: usize);
+}
+
+// Where the locations and extents of the results are written alongside
+// their attribute columns
+pub trait Store: Sized {
+    type Column: ColumnStore;
+
+    // Whether attributes that are not one of the fields are kept as columns
+    const KEEP_OTHER: bool;
+
+    fn new(n: usize, with_extents: bool) -> Self;
+    // Results without a location have missing coordinates
+    fn set_location(&mut self, row: usize, x: Option<f64>, y: Option<f64>);
+    fn set_extent(&mut self, row: usize, x: Extent);
+    fn clear(&mut self, row: usize);
+    fn copy(&mut self, from: usize, to: usize);
+}
+
+// An R vector of an attribute
+pub enum RColumn {
+    Lgl(Logicals),
+    Int(Integers),
+    Dbl(Doubles),
+    Str(Strings),
+}
+
+impl ColumnStore for RColumn {
+    fn new(kind: Kind, n: usize) -> Self {
+        match kind {
+            Kind::Lgl => RColumn::Lgl(Logicals::from_values((0..n).map(|_| Rbool::na()))),
+            Kind::Int => RColumn::Int(Integers::from_values((0..n).map(|_| Rint::na()))),
+            Kind::Dbl => RColumn::Dbl(Doubles::from_values((0..n).map(|_| Rfloat::na()))),
+            Kind::Str => RColumn::Str(Strings::from_values((0..n).map(|_| Rstr::na()))),
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        match self {
+            RColumn::Lgl(_) => Kind::Lgl,
+            RColumn::Int(_) => Kind::Int,
+            RColumn::Dbl(_) => Kind::Dbl,
+            RColumn::Str(_) => Kind::Str,
+        }
+    }
+
+    fn set_lgl(&mut self, row: usize, x: bool) {
+        if let RColumn::Lgl(c) = self {
+            c[row] = Rbool::from(x);
+        }
+    }
+
+    fn set_int(&mut self, row: usize, x: i32) {
+        if let RColumn::Int(c) = self {
+            c[row] = Rint::from(x);
+        }
+    }
+
+    fn set_dbl(&mut self, row: usize, x: f64) {
+        if let RColumn::Dbl(c) = self {
+            c[row] = Rfloat::from(x);
+        }
+    }
+
+    fn set_str(&mut self, row: usize, x: &str) {
+        if let RColumn::Str(c) = self {
+            c.set_elt(row, Rstr::from(x));
+        }
+    }
+
+    fn clear(&mut self, row: usize) {
+        match self {
+            RColumn::Lgl(c) => c[row] = Rbool::na(),
+            RColumn::Int(c) => c[row] = Rint::na(),
+            RColumn::Dbl(c) => c[row] = Rfloat::na(),
+            RColumn::Str(c) => c.set_elt(row, Rstr::na()),
+        }
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        match self {
+            RColumn::Lgl(c) => c[to] = c[from],
+            RColumn::Int(c) => c[to] = c[from],
+            RColumn::Dbl(c) => c[to] = c[from],
+            RColumn::Str(c) => {
+                let v = c.elt(from);
+                c.set_elt(to, v);
+            }
+        }
+    }
+}
+
+impl RColumn {
+    fn into_robj(self) -> Robj {
+        match self {
+            RColumn::Lgl(c) => c.into_robj(),
+            RColumn::Int(c) => c.into_robj(),
+            RColumn::Dbl(c) => c.into_robj(),
+            RColumn::Str(c) => c.into_robj(),
+        }
+    }
+}
+
+// The `sfg` of each location and the named extent of each candidate
+pub struct RStore {
+    locations: List,
+    extents: Option<List>,
+}
+
+impl Store for RStore {
+    type Column = RColumn;
+    const KEEP_OTHER: bool = true;
+
+    fn new(n: usize, with_extents: bool) -> Self {
+        RStore {
+            locations: List::new(n),
+            extents: with_extents.then(|| List::new(n)),
+        }
+    }
+
+    fn set_location(&mut self, row: usize, x: Option<f64>, y: Option<f64>) {
+        let _ = self.locations.set_elt(row, convert::sfg_point(x, y));
+    }
+
+    fn set_extent(&mut self, row: usize, x: Extent) {
+        if let Some(extents) = self.extents.as_mut() {
+            let mut extent = Doubles::from_values([x.xmin, x.ymin, x.xmax, x.ymax]);
+            let _ = extent.set_attrib("names", ["xmin", "ymin", "xmax", "ymax"]);
+            let _ = extents.set_elt(row, extent.into_robj());
+        }
+    }
+
+    fn clear(&mut self, row: usize) {
+        let _ = self.locations.set_elt(row, ().into_robj());
+        if let Some(extents) = self.extents.as_mut() {
+            let _ = extents.set_elt(row, ().into_robj());
+        }
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        if let Ok(point) = self.locations.elt(from) {
+            let _ = self.locations.set_elt(to, point);
+        }
+        if let Some(extents) = self.extents.as_mut() {
+            if let Ok(extent) = extents.elt(from) {
+                let _ = extents.set_elt(to, extent);
+            }
+        }
+    }
+}
+
+// Attributes that are not one of the fields are `Pending` until a value
+// that is not missing is found.
+enum Column<C> {
+    Pending,
+    Ready(C),
+}
+
+impl<C: ColumnStore> Column<C> {
+    fn new(kind: Kind, n: usize) -> Self {
+        Column::Ready(C::new(kind, n))
+    }
+
+    // Reads the next value of `map` into `row`. Null values and empty
+    // strings are left missing. Values are coerced to the kind of the column
+    // and the text of a value that cannot be represented is returned.
+    fn read<'de, A: MapAccess<'de>>(
+        &mut self,
+        row: usize,
+        map: &mut A,
+    ) -> Result<Option<String>, A::Error> {
+        match self {
+            Column::Pending => {
+                map.next_value::<IgnoredAny>()?;
+                Ok(None)
+            }
+            Column::Ready(c) => map.next_value_seed(CellSeed { column: c, row }),
+        }
+    }
+
+    // Sets `row` to a value of an attribute that is not one of the fields.
+    // The type of a pending column is inferred from the first value that is
+    // not missing. Values that cannot be represented by the column are left
+    // missing.
+    fn set(&mut self, row: usize, n: usize, x: &Value) {
+        if let Column::Pending = self {
+            match Kind::infer(x) {
+                Some(kind) => *self = Column::new(kind, n),
+                None => return,
+            }
+        }
+
+        let c = match self {
+            Column::Ready(c) => c,
+            Column::Pending => return,
+        };
+
+        match (c.kind(), x) {
+            (_, Value::Null) => {}
+            (Kind::Lgl, Value::Bool(b)) => c.set_lgl(row, *b),
+            (Kind::Int, Value::Number(v)) => {
+                if let Some(i) = v.as_i64().and_then(|i| i32::try_from(i).ok()) {
+                    c.set_int(row, i);
+                }
+            }
+            (Kind::Dbl, Value::Number(v)) => {
+                if let Some(f) = v.as_f64() {
+                    c.set_dbl(row, f);
+                }
+            }
+            (Kind::Dbl, Value::String(v)) => {
+                if let Ok(f) = v.trim().parse::<f64>() {
+                    c.set_dbl(row, f);
+                }
+            }
+            (Kind::Str, Value::String(v)) if v.is_empty() => {}
+            (Kind::Str, Value::String(v)) => c.set_str(row, v),
+            (Kind::Str, v) => c.set_str(row, &v.to_string()),
+            _ => {}
+        }
+    }
+
+    fn clear(&mut self, row: usize) {
+        if let Column::Ready(c) = self {
+            c.clear(row);
+        }
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        if let Column::Ready(c) = self {
+            c.copy(from, to);
+        }
+    }
+
+    // The vector of the column. Pending columns never had a value.
+    fn into_store(self) -> Option<C> {
+        match self {
+            Column::Pending => None,
+            Column::Ready(c) => Some(c),
+        }
+    }
+}
+
+// Writes a value into its column without an intermediate `Value`. Values
+// are coerced like `schema::Decoder` does: numbers and booleans are written
+// as text into string columns and numeric strings are parsed into number
+// columns. A value that cannot be represented is left missing and its text
+// is returned so that it is reported as a problem.
+struct CellSeed<'a, C> {
+    column: &'a mut C,
+    row: usize,
+}
+
+impl<C: ColumnStore> CellSeed<'_, C> {
+    // Sets a number. `int` is the number if it is a 32-bit integer.
+    fn number(self, x: f64, int: Option<i32>, text: impl FnOnce() -> String) -> Option<String> {
+        match (self.column.kind(), int) {
+            (Kind::Lgl, _) => self.column.set_lgl(self.row, x != 0.0),
+            (Kind::Int, Some(i)) => self.column.set_int(self.row, i),
+            (Kind::Int, None) => return Some(text()),
+            (Kind::Dbl, _) => self.column.set_dbl(self.row, x),
+            (Kind::Str, _) => self.column.set_str(self.row, &text()),
+        }
+        None
+    }
+
+    // Sets an array or object. Only string columns can represent these.
+    fn nested(self, x: Value) -> Option<String> {
+        match self.column.kind() {
+            Kind::Str => {
+                self.column.set_str(self.row, &x.to_string());
+                None
+            }
+            _ => Some(x.to_string()),
+        }
+    }
+}
+
+impl<'de, C: ColumnStore> DeserializeSeed<'de> for CellSeed<'_, C> {
+    type Value = Option<String>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de, C: ColumnStore> Visitor<'de> for CellSeed<'_, C> {
+    type Value = Option<String>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the value of an attribute")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Self::Value, E> {
+        match self.column.kind() {
+            Kind::Lgl => self.column.set_lgl(self.row, v),
+            Kind::Str => self.column.set_str(self.row, &v.to_string()),
+            _ => return Ok(Some(v.to_string())),
+        }
+        Ok(None)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(self.number(v as f64, i32::try_from(v).ok(), || v.to_string()))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(self.number(v as f64, i32::try_from(v).ok(), || v.to_string()))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        let fits = v.fract() == 0.0 && v >= i32::MIN as f64 && v <= i32::MAX as f64;
+        Ok(self.number(v, fits.then_some(v as i32), || Value::from(v).to_string()))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let (column, row) = (self.column, self.row);
+        let trimmed = v.trim();
+        let invalid = || Ok(Some(Value::from(v).to_string()));
+
+        match column.kind() {
+            Kind::Str if v.is_empty() => {}
+            Kind::Str => column.set_str(row, v),
+            _ if trimmed.is_empty() => {}
+            Kind::Lgl => match trimmed.to_lowercase().as_str() {
+                "true" => column.set_lgl(row, true),
+                "false" => column.set_lgl(row, false),
+                _ => return invalid(),
+            },
+            Kind::Int => match trimmed.parse::<i64>().map(i32::try_from) {
+                Ok(Ok(i)) => column.set_int(row, i),
+                _ => return invalid(),
+            },
+            Kind::Dbl => match trimmed.parse::<f64>() {
+                Ok(f) => column.set_dbl(row, f),
+                Err(_) => return invalid(),
+            },
+        }
+        Ok(None)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+        let x = Value::deserialize(SeqAccessDeserializer::new(seq))?;
+        Ok(self.nested(x))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        let x = Value::deserialize(MapAccessDeserializer::new(map))?;
+        Ok(self.nested(x))
+    }
+}
+
+// Attribute columns and their names
+pub type NamedColumns<C> = Vec<(String, Option<C>)>;
+
+// The attribute columns of the results. Attributes that are not one of the
+// fields are added as columns named after the attribute when they are first
+// found so that they are never dropped unless `keep_other` is false.
+// Values of fields that could not be coerced to their kind are `problems`.
+struct AttributeColumns<C> {
+    fields: Cow<'static, [AttributeField]>,
+    columns: Vec<Column<C>>,
+    extra: Vec<(String, Column<C>)>,
+    keep_other: bool,
+    n: usize,
+    problems: Vec<Problem>,
+}
+
+impl<C: ColumnStore> AttributeColumns<C> {
+    fn new(fields: Cow<'static, [AttributeField]>, n: usize, keep_other: bool) -> Self {
+        let columns = fields.iter().map(|f| Column::new(f.kind, n)).collect();
+        AttributeColumns {
+            fields,
+            columns,
+            extra: Vec::new(),
+            keep_other,
+            n,
+            problems: Vec::new(),
+        }
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Column<C>> {
+        let extra = self.extra.iter_mut().map(|(_, c)| c);
+        self.columns.iter_mut().chain(extra)
+    }
+
+    // The columns named after their field followed by the extra columns.
+    // Extra columns without a value are `None`.
+    fn into_named(self) -> NamedColumns<C> {
+        let names = self
+            .fields
+            .iter()
+            .map(|f| f.column.to_string())
+            .chain(self.extra.iter().map(|(nm, _)| nm.clone()))
+            .collect::<Vec<_>>();
+        let columns = self
+            .columns
+            .into_iter()
+            .chain(self.extra.into_iter().map(|(_, c)| c))
+            .map(Column::into_store);
+        names.into_iter().zip(columns).collect()
+    }
+}
+
+// The column of an attribute that is not one of the fields
+fn extra_column<C>(extra: &mut Vec<(String, Column<C>)>, name: String) -> &mut Column<C> {
+    let i = match extra.iter().position(|(nm, _)| *nm == name) {
+        Some(i) => i,
+        None => {
+            extra.push((name, Column::Pending));
+            extra.len() - 1
+        }
+    };
+    &mut extra[i].1
+}
+
+impl AttributeColumns<RColumn> {
+    // The data.frame of the columns. Values that could not be coerced are
+    // described by its `problems` attribute.
+    fn into_dataframe(mut self) -> Robj {
+        let n = self.n;
+        let problems = std::mem::take(&mut self.problems);
+        let (names, values): (Vec<_>, Vec<_>) = self
+            .into_named()
+            .into_iter()
+            .map(|(nm, c)| {
+                let v = match c {
+                    Some(c) => c.into_robj(),
+                    None => Logicals::from_values((0..n).map(|_| Rbool::na())).into_robj(),
+                };
+                (nm, v)
+            })
+            .unzip();
+        let mut res = match List::from_names_and_values(names, values) {
+            Ok(l) => l.into_robj(),
+            Err(e) => throw_r_error(format!("Failed to create data.frame: {e}")),
+        };
+
+        // the compact form of row names used by R
+        let row_names = Integers::from_values([Rint::na(), Rint::from(-(n as i32))]);
+        let _ = res.set_attrib("row.names", row_names);
+        let _ = res.set_class(&["data.frame"]);
+        if !problems.is_empty() {
+            let _ = res.set_attrib("problems", schema::problems_as_robj(&problems));
+        }
+        res
+    }
+}
+
+// The key of an attribute
+enum Key {
+    // the position of a field
+    Field(usize),
+    // the name of an attribute that is not one of the fields
+    Other(String),
+}
+
+// Reads the keys of an attributes object as the position of their field.
+// Services return the fields in the same order so the field after the
+// previous one is checked first.
+struct KeySeed<'a> {
+    fields: &'a [AttributeField],
+    hint: usize,
+}
+
+impl<'de> DeserializeSeed<'de> for KeySeed<'_> {
+    type Value = Key;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+}
+
+impl<'de> Visitor<'de> for KeySeed<'_> {
+    type Value = Key;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the name of an attribute")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        if self.fields.get(self.hint).map_or(false, |f| f.name == v) {
+            return Ok(Key::Field(self.hint));
+        }
+        match self.fields.iter().position(|f| f.name == v) {
+            Some(i) => Ok(Key::Field(i)),
+            None => Ok(Key::Other(v.to_string())),
+        }
+    }
+}
+
+// Reads the attributes object of the result at `row`. Attributes that are
+// not one of the fields are read into extra columns or skipped.
+struct AttributesSeed<'a, C> {
+    columns: &'a mut AttributeColumns<C>,
+    row: usize,
+}
+
+impl<'de, C: ColumnStore> DeserializeSeed<'de> for AttributesSeed<'_, C> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, C: ColumnStore> Visitor<'de> for AttributesSeed<'_, C> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object of attributes")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let AttributeColumns {
+            fields,
+            columns,
+            extra,
+            keep_other,
+            n,
+            problems,
+        } = &mut *self.columns;
+        let (fields, keep_other, n) = (&**fields, *keep_other, *n);
+        let mut hint = 0;
+
+        while let Some(key) = map.next_key_seed(KeySeed { fields, hint })? {
+            match key {
+                Key::Field(i) => {
+                    if let Some(value) = columns[i].read(self.row, &mut map)? {
+                        problems.push(Problem {
+                            row: self.row + 1,
+                            field: fields[i].name.to_string(),
+                            value,
+                        });
+                    }
+                    hint = i + 1;
+                }
+                Key::Other(name) if keep_other => {
+                    let value = map.next_value::<Value>()?;
+                    extra_column(extra, name).set(self.row, n, &value);
+                }
+                Key::Other(_) => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The coordinates of a location. Coordinates that are not numbers, such as
+// the `"NaN"` of unmatched results, are missing.
+#[derive(Deserialize)]
+struct Location {
+    #[serde(default, deserialize_with = "coordinate")]
+    x: Option<f64>,
+    #[serde(default, deserialize_with = "coordinate")]
+    y: Option<f64>,
+}
+
+fn coordinate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    Ok(Value::deserialize(deserializer)?.as_f64())
+}
+
+// The ResultID of a result. Other attributes are skipped.
+#[derive(Deserialize)]
+struct ScannedAttributes {
+    #[serde(rename = "ResultID", default, deserialize_with = "result_id")]
+    result_id: Option<i32>,
+}
+
+// ResultIDs are coerced like the values of integer fields. Those that are
+// not an integer are missing.
+fn result_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<i32>, D::Error> {
+    let id = match Value::deserialize(deserializer)? {
+        Value::Number(n) => n
+            .as_i64()
+            .or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i64)),
+        Value::String(s) => s.trim().parse::<i64>().ok(),
+        _ => None,
+    };
+    Ok(id.and_then(|i| i32::try_from(i).ok()))
+}
+
+#[derive(Deserialize)]
+struct Scanned {
+    attributes: Option<ScannedAttributes>,
+}
+
+#[derive(Deserialize)]
+struct Scan {
+    #[serde(alias = "candidates")]
+    locations: Option<Vec<Scanned>>,
+}
+
+// The ResultID of every result of a response in the order they are
+// returned without reading their other values
+fn scan(x: &str) -> serde_json::Result<Vec<Option<i32>>> {
+    let ids = serde_json::from_str::<Scan>(x)?
+        .locations
+        .unwrap_or_default()
+        .into_iter()
+        .map(|s| s.attributes.and_then(|a| a.result_id))
+        .collect();
+    Ok(ids)
+}
+
+// The rows that the results with `ids` are read into. The ResultID of a
+// result is its 1-based row. Results whose ResultID is not a row or whose
+// row is already filled are not read and their ResultIDs are returned.
+pub fn place(ids: &[Option<i32>], filled: &[bool]) -> (Vec<Option<usize>>, Vec<i32>) {
+    let mut claimed = filled.to_vec();
+    let mut unplaced = Vec::new();
+
+    let targets = ids
+        .iter()
+        .map(|id| {
+            let id = id.unwrap_or(0);
+            let row = usize::try_from(id)
+                .ok()
+                .and_then(|i| i.checked_sub(1))
+                .filter(|&r| r < claimed.len() && !claimed[r]);
+
+            match row {
+                Some(r) => claimed[r] = true,
+                None => unplaced.push(id),
+            }
+            row
+        })
+        .collect();
+
+    (targets, unplaced)
+}
+
+// The results of one or more responses parsed into the columns of a
+// `Store`. These are R vectors unless another store is given.
+pub struct Results<S: Store = RStore> {
+    attributes: AttributeColumns<S::Column>,
+    store: S,
+    // the rows that a result was read into
+    filled: Vec<bool>,
+    n: usize,
+    spatial_reference: Option<SpatialReference>,
+}
+
+impl<S: Store> Results<S> {
+    pub fn new(
+        fields: impl Into<Cow<'static, [AttributeField]>>,
+        n: usize,
+        with_extents: bool,
+    ) -> Self {
+        Results {
+            attributes: AttributeColumns::new(fields.into(), n, S::KEEP_OTHER),
+            store: S::new(n, with_extents),
+            filled: vec![false; n],
+            n,
+            spatial_reference: None,
+        }
+    }
+
+    pub fn n_results(&self) -> usize {
+        self.n
+    }
+
+    pub fn is_filled(&self, row: usize) -> bool {
+        self.filled.get(row).copied().unwrap_or(false)
+    }
+
+    // The spatial reference of the first response that was read
+    pub fn spatial_reference(&self) -> Option<&SpatialReference> {
+        self.spatial_reference.as_ref()
+    }
+
+    // Reads every result of a response into the row of its ResultID. Returns
+    // the ResultIDs that are not a row or whose row was already filled.
+    // These are skipped. When the response cannot be parsed none of its
+    // results are kept.
+    pub fn read_aligned(&mut self, x: &str) -> serde_json::Result<Vec<i32>> {
+        let (targets, unplaced) = place(&scan(x)?, &self.filled);
+        self.read(x, &targets)?;
+        Ok(unplaced)
+    }
+
+    // Reads the address and location of a /reverseGeocode response into
+    // `row`. When the response cannot be parsed the row is left empty.
+    pub fn read_address(&mut self, x: &str, row: usize) -> serde_json::Result<()> {
+        let mut de = serde_json::Deserializer::from_str(x);
+        let res = AddressSeed {
+            results: &mut *self,
+            row,
+        }
+        .deserialize(&mut de)
+        .and_then(|_| de.end());
+
+        if res.is_err() {
+            self.clear_row(row);
+        }
+
+        res
+    }
+
+    // Reads the i-th result of the response into the row `targets[i]`
+    fn read(&mut self, x: &str, targets: &[Option<usize>]) -> serde_json::Result<()> {
+        let had_sr = self.spatial_reference.is_some();
+        let mut de = serde_json::Deserializer::from_str(x);
+        let res = ResponseSeed {
+            results: &mut *self,
+            targets,
+        }
+        .deserialize(&mut de)
+        .and_then(|_| de.end());
+
+        if res.is_err() {
+            for &row in targets.iter().flatten() {
+                self.clear_row(row);
+            }
+            if !had_sr {
+                self.spatial_reference = None;
+            }
+        }
+
+        res
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        for col in self.attributes.iter_mut() {
+            col.clear(row);
+        }
+        self.attributes.problems.retain(|p| p.row != row + 1);
+        self.store.clear(row);
+        self.filled[row] = false;
+    }
+
+    // Copies the result of row `from` to row `to`
+    pub fn copy_row(&mut self, from: usize, to: usize) {
+        for col in self.attributes.iter_mut() {
+            col.copy(from, to);
+        }
+        let problems = &mut self.attributes.problems;
+        problems.retain(|p| p.row != to + 1);
+        for i in 0..problems.len() {
+            if problems[i].row == from + 1 {
+                let copied = Problem {
+                    row: to + 1,
+                    ..problems[i].clone()
+                };
+                problems.push(copied);
+            }
+        }
+        self.store.copy(from, to);
+        self.filled[to] = self.filled[from];
+    }
+
+    // Sets the integer attribute `name` to the 1-based row of every result
+    pub fn number_rows(&mut self, name: &str) {
+        let i = self.attributes.fields.iter().position(|f| f.name == name);
+        if let Some(Column::Ready(c)) = i.map(|i| &mut self.attributes.columns[i]) {
+            for row in 0..self.n {
+                c.set_int(row, row as i32 + 1);
+            }
+        }
+    }
+
+    // The values that could not be coerced to the kind of their field. Their
+    // rows are 1-based.
+    pub fn problems(&self) -> &[Problem] {
+        &self.attributes.problems
+    }
+
+    // The named attribute columns, the store, and the rows that were
+    // filled. Columns of attributes that never had a value are `None`.
+    pub fn into_parts(self) -> (NamedColumns<S::Column>, S, Vec<bool>) {
+        (self.attributes.into_named(), self.store, self.filled)
+    }
+}
+
+impl Results<RStore> {
+    // Results with `n` rows that the results of /geocodeAddresses responses
+    // are read into by their ResultID. See `read_aligned()`.
+    pub fn aligned(fields: impl Into<Cow<'static, [AttributeField]>>, n: usize) -> Self {
+        Results::new(fields, n, false)
+    }
+
+    // Results with `n` rows that /reverseGeocode responses are read into.
+    // See `read_address()`.
+    pub fn addresses(fields: impl Into<Cow<'static, [AttributeField]>>, n: usize) -> Self {
+        Results::new(fields, n, false)
+    }
+
+    // A list of the attributes data.frame, the extents of candidates, the
+    // `sfg` of each location, and the spatial reference. This is turned
+    // into an sf object in R. Rows without a result have an empty location.
+    pub fn into_robj(mut self) -> Robj {
+        for row in (0..self.n).filter(|&r| !self.filled[r]) {
+            self.store.set_location(row, None, None);
+        }
+
+        let attributes = self.attributes.into_dataframe();
+        let sr = convert::serialize(&self.spatial_reference);
+        let RStore { locations, extents } = self.store;
+
+        match extents {
+            Some(extents) => list!(
+                attributes = attributes,
+                extents = extents,
+                locations = locations,
+                sr = sr
+            ),
+            None => list!(attributes = attributes, locations = locations, sr = sr),
+        }
+        .into_robj()
+    }
+}
+
+// Reads a result into the row `target`. Results without a target are skipped.
+struct RowSeed<'a, S: Store> {
+    results: &'a mut Results<S>,
+    target: Option<usize>,
+}
+
+impl<'de, S: Store> DeserializeSeed<'de> for RowSeed<'_, S> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        match self.target {
+            Some(_) => deserializer.deserialize_map(self),
+            None => deserializer.deserialize_ignored_any(IgnoredAny).map(|_| ()),
+        }
+    }
+}
+
+impl<'de, S: Store> Visitor<'de> for RowSeed<'_, S> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a result")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let res = self.results;
+        let row = match self.target {
+            Some(r) if r < res.n => r,
+            _ => return Err(de::Error::custom("more results than were counted")),
+        };
+
+        let mut location = None;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "location" => location = map.next_value::<Option<Location>>()?,
+                "attributes" => map.next_value_seed(AttributesSeed {
+                    columns: &mut res.attributes,
+                    row,
+                })?,
+                "extent" => {
+                    if let Some(e) = map.next_value::<Option<Extent>>()? {
+                        res.store.set_extent(row, e);
+                    }
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        let (x, y) = location.map_or((None, None), |l| (l.x, l.y));
+        res.store.set_location(row, x, y);
+        res.filled[row] = true;
+
+        Ok(())
+    }
+}
+
+// Reads a /reverseGeocode response into `row`. Its `address` is read like
+// the attributes of a result.
+struct AddressSeed<'a, S: Store> {
+    results: &'a mut Results<S>,
+    row: usize,
+}
+
+impl<'de, S: Store> DeserializeSeed<'de> for AddressSeed<'_, S> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, S: Store> Visitor<'de> for AddressSeed<'_, S> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a response of /reverseGeocode")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let res = self.results;
+        let row = self.row;
+        if row >= res.n {
+            return Err(de::Error::custom("more responses than rows"));
+        }
+
+        let mut location = None;
+        let mut has_address = false;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "address" => {
+                    map.next_value_seed(AttributesSeed {
+                        columns: &mut res.attributes,
+                        row,
+                    })?;
+                    has_address = true;
+                }
+                "location" => location = Some(map.next_value::<Location>()?),
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        if !has_address {
+            return Err(de::Error::missing_field("address"));
+        }
+        let location = location.ok_or_else(|| de::Error::missing_field("location"))?;
+
+        res.store.set_location(row, location.x, location.y);
+        res.filled[row] = true;
+        Ok(())
+    }
+}
+
+// Reads every result of the `locations` or `candidates` array
+struct RowsSeed<'a, S: Store> {
+    results: &'a mut Results<S>,
+    targets: &'a [Option<usize>],
+}
+
+impl<'de, S: Store> DeserializeSeed<'de> for RowsSeed<'_, S> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, S: Store> Visitor<'de> for RowsSeed<'_, S> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an array of results")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut i = 0;
+        loop {
+            let seed = RowSeed {
+                results: &mut *self.results,
+                target: self.targets.get(i).copied().flatten(),
+            };
+            if seq.next_element_seed(seed)?.is_none() {
+                break;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+// Reads a response. The spatial reference of the first response is kept.
+struct ResponseSeed<'a, S: Store> {
+    results: &'a mut Results<S>,
+    targets: &'a [Option<usize>],
+}
+
+impl<'de, S: Store> DeserializeSeed<'de> for ResponseSeed<'_, S> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, S: Store> Visitor<'de> for ResponseSeed<'_, S> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a response of a GeocodeServer")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut found = false;
+        let mut has_sr = false;
+
+        while let Some(key) = map.next_key::<String>()? {
+            match key.as_str() {
+                "spatialReference" => {
+                    let sr = map.next_value::<SpatialReference>()?;
+                    self.results.spatial_reference.get_or_insert(sr);
+                    has_sr = true;
+                }
+                "locations" | "candidates" => {
+                    map.next_value_seed(RowsSeed {
+                        results: &mut *self.results,
+                        targets: self.targets,
+                    })?;
+                    found = true;
+                }
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        if !has_sr {
+            return Err(de::Error::missing_field("spatialReference"));
+        }
+        if !found {
+            return Err(de::Error::missing_field("locations"));
+        }
+        Ok(())
+    }
+}
+
+// Parses a response of /findAddressCandidates or /geocodeAddresses with one
+// row per result in the order they are returned. `with_extents` reads the
+// extent of each candidate.
+pub fn parse_into<S: Store>(
+    x: &str,
+    fields: impl Into<Cow<'static, [AttributeField]>>,
+    with_extents: bool,
+) -> serde_json::Result<Results<S>> {
+    let n = scan(x)?.len();
+    let mut res = Results::new(fields, n, with_extents);
+    let targets = (0..n).map(Some).collect::<Vec<_>>();
+    res.read(x, &targets)?;
+    Ok(res)
+}
+
+// Parses a response into R vectors. See `parse_into()`.
+pub fn parse_results(
+    x: &str,
+    fields: impl Into<Cow<'static, [AttributeField]>>,
+    with_extents: bool,
+) -> serde_json::Result<Results> {
+    parse_into(x, fields, with_extents)
+}
@@ -0,0 +1,416 @@
+use crate::geoarrow;
+use crate::wkb::{self, Coords};
+use extendr_api::{deserializer::from_robj, prelude::*};
+use serde::Serialize;
+use serde_esri::{geometry::EsriPoint, spatial_reference::SpatialReference};
+use std::fmt;
+
+// An input from R that could not be converted. It names the argument and,
+// when known, the row and the offending value so that the R error points
+// at the problem instead of the R session being taken down by a panic.
+#[derive(Debug, Clone)]
+pub struct ConversionError {
+    pub arg: String,
+    pub row: Option<usize>,
+    pub value: Option<String>,
+    pub message: String,
+}
+
+impl ConversionError {
+    pub fn new(arg: &str, message: impl Into<String>) -> Self {
+        ConversionError {
+            arg: arg.to_string(),
+            row: None,
+            value: None,
+            message: message.into(),
+        }
+    }
+
+    // `row` is 0-based and reported 1-based
+    pub fn at(mut self, row: usize) -> Self {
+        self.row = Some(row + 1);
+        self
+    }
+
+    pub fn found(mut self, x: &Robj) -> Self {
+        self.value = Some(describe(x));
+        self
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`", self.arg)?;
+        if let Some(row) = self.row {
+            write!(f, " at row {row}")?;
+        }
+        write!(f, " {}", self.message)?;
+        if let Some(value) = &self.value {
+            write!(f, ", found {value}")?;
+        }
+        Ok(())
+    }
+}
+
+pub type Converted<T> = std::result::Result<T, ConversionError>;
+
+// Signals a conversion error as an R error
+pub trait OrThrow<T> {
+    fn or_throw(self) -> T;
+}
+
+impl<T> OrThrow<T> for Converted<T> {
+    fn or_throw(self) -> T {
+        match self {
+            Ok(x) => x,
+            Err(e) => throw_r_error(e.to_string()),
+        }
+    }
+}
+
+// A short description of an R object used in error messages
+pub fn describe(x: &Robj) -> String {
+    if x.is_null() {
+        return String::from("`NULL`");
+    }
+
+    let class = x
+        .class()
+        .map(|c| c.collect::<Vec<_>>().join("/"))
+        .unwrap_or_else(|| format!("{:?}", x.rtype()).to_lowercase());
+
+    let value = match (x.len(), x.as_str(), x.as_real()) {
+        (1, Some(s), _) => Some(s.to_string()),
+        (1, _, Some(r)) => Some(r.to_string()),
+        _ => None,
+    };
+
+    match value {
+        Some(v) => format!("<{class}> `{v}`"),
+        None => format!("<{class}> of length {}", x.len()),
+    }
+}
+
+// A positive count such as `batch_size` or `max_active`
+pub fn count(arg: &str, x: i32) -> Converted<usize> {
+    if x < 1 {
+        return Err(ConversionError::new(
+            arg,
+            format!("must be at least 1, found {x}"),
+        ));
+    }
+    Ok(x as usize)
+}
+
+pub fn spatial_reference(arg: &str, x: &Robj) -> Converted<SpatialReference> {
+    from_robj::<SpatialReference>(x).map_err(|e| {
+        ConversionError::new(arg, format!("must be a spatial reference ({e})")).found(x)
+    })
+}
+
+// A spatial reference that is only required when `NULL` is not supplied
+pub fn optional_spatial_reference(arg: &str, x: &Robj) -> Converted<Option<SpatialReference>> {
+    if x.is_null() {
+        return Ok(None);
+    }
+    spatial_reference(arg, x).map(Some)
+}
+
+// The coordinate dimensions of a point
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Dims {
+    Xy,
+    Xyz,
+    Xym,
+    Xyzm,
+}
+
+impl Dims {
+    // The dimensions of an `sfg` are the first element of its class.
+    // Unclassed vectors are XYZ or XYZM depending on their length.
+    fn of(x: &Robj, len: usize) -> Self {
+        let class = x.class().and_then(|mut c| c.next());
+        match (class, len) {
+            (Some("XYZM"), _) | (None, 4..) => Dims::Xyzm,
+            (Some("XYZ"), _) | (None, 3) => Dims::Xyz,
+            (Some("XYM"), _) => Dims::Xym,
+            _ => Dims::Xy,
+        }
+    }
+}
+
+// A coordinate that is neither NA nor NaN
+fn coord(x: &Doubles, i: usize) -> Option<f64> {
+    if i >= x.len() {
+        return None;
+    }
+    let v = x[i].inner();
+    if v.is_nan() {
+        None
+    } else {
+        Some(v)
+    }
+}
+
+// Creates an Esri point from coordinates
+pub fn esri_point(c: Coords, sr: &SpatialReference) -> EsriPoint {
+    EsriPoint {
+        x: c.x,
+        y: c.y,
+        z: c.z,
+        m: c.m,
+        spatialReference: Some(sr.clone()),
+    }
+}
+
+// Converts the point at `row`. Empty points and points with a missing
+// x or y coordinate are `None` so that they are never sent. Z and M
+// values are kept for XYZ, XYM, and XYZM points. Raw vectors are read
+// as WKB and `NULL` is a missing point.
+pub fn point(
+    arg: &str,
+    row: usize,
+    x: Robj,
+    sr: &SpatialReference,
+) -> Converted<Option<EsriPoint>> {
+    if x.is_null() {
+        return Ok(None);
+    }
+
+    if let Some(bytes) = x.as_raw_slice() {
+        return wkb::parse_point(bytes)
+            .map(|c| c.map(|c| esri_point(c, sr)))
+            .map_err(|e| ConversionError::new(arg, e).at(row));
+    }
+
+    if x.inherits("sfg") && !x.inherits("POINT") {
+        return Err(ConversionError::new(arg, "must be a POINT")
+            .at(row)
+            .found(&x));
+    }
+
+    let crds = match Doubles::try_from(x.clone()) {
+        Ok(c) => c,
+        Err(_) => {
+            let msg = "must be a numeric vector of coordinates";
+            return Err(ConversionError::new(arg, msg).at(row).found(&x));
+        }
+    };
+
+    let (px, py) = match (coord(&crds, 0), coord(&crds, 1)) {
+        (Some(px), Some(py)) => (px, py),
+        _ => return Ok(None),
+    };
+
+    let (z, m) = match Dims::of(&x, crds.len()) {
+        Dims::Xy => (None, None),
+        Dims::Xyz => (coord(&crds, 2), None),
+        Dims::Xym => (None, coord(&crds, 2)),
+        Dims::Xyzm => (coord(&crds, 2), coord(&crds, 3)),
+    };
+
+    Ok(Some(EsriPoint {
+        x: px,
+        y: py,
+        z,
+        m,
+        spatialReference: Some(sr.clone()),
+    }))
+}
+
+// Converts an `sfc` of points. Other geometry types are an error
+pub fn sfc_points(arg: &str, x: List, sr: &SpatialReference) -> Converted<Vec<Option<EsriPoint>>> {
+    if !x.inherits("sfc") {
+        let robj = x.into_robj();
+        return Err(ConversionError::new(arg, "must be an `sfc` of points").found(&robj));
+    }
+
+    points(arg, x, sr)
+}
+
+// Converts every element of a list of points
+pub fn points(arg: &str, x: List, sr: &SpatialReference) -> Converted<Vec<Option<EsriPoint>>> {
+    x.into_iter()
+        .enumerate()
+        .map(|(i, (_, pi))| point(arg, i, pi, sr))
+        .collect()
+}
+
+// Converts columns of coordinates. `z` is optional. Rows with a missing
+// x or y coordinate are `None`.
+pub fn coordinates(
+    x: &Doubles,
+    y: &Doubles,
+    z: Option<&Doubles>,
+    sr: &SpatialReference,
+) -> Converted<Vec<Option<EsriPoint>>> {
+    let n = x.len();
+    check_len("y", y.len(), n)?;
+    if let Some(z) = z {
+        check_len("z", z.len(), n)?;
+    }
+
+    let res = (0..n)
+        .map(|i| {
+            let (px, py) = (coord(x, i)?, coord(y, i)?);
+            Some(EsriPoint {
+                x: px,
+                y: py,
+                z: z.and_then(|z| coord(z, i)),
+                m: None,
+                spatialReference: Some(sr.clone()),
+            })
+        })
+        .collect();
+
+    Ok(res)
+}
+
+// Converts locations that are an `sfc` of points, a list of WKB raw
+// vectors, a `nanoarrow_array` of geoarrow points, or a list of numeric
+// `x`, `y`, and optionally `z` columns. `sr` is only optional for arrays
+// that describe their own CRS.
+pub fn locations(
+    arg: &str,
+    x: Robj,
+    sr: Option<&SpatialReference>,
+) -> Converted<Vec<Option<EsriPoint>>> {
+    if x.inherits("nanoarrow_array") {
+        return geoarrow::points(arg, &x, sr);
+    }
+
+    let sr = sr.ok_or_else(|| ConversionError::new("sr", "must be provided"))?;
+
+    let list = List::try_from(x.clone()).map_err(|_| {
+        let msg = "must be an `sfc` of points, WKB, geoarrow points, or coordinates";
+        ConversionError::new(arg, msg).found(&x)
+    })?;
+
+    if list.inherits("sfc") {
+        return sfc_points(arg, list, sr);
+    }
+
+    let is_wkb = list.inherits("wk_wkb")
+        || list.inherits("blob")
+        || (list.values().any(|v| v.rtype() == Rtype::Raw)
+            && list
+                .values()
+                .all(|v| v.is_null() || v.rtype() == Rtype::Raw));
+
+    if is_wkb {
+        return points(arg, list, sr);
+    }
+
+    let col = |name: &str| -> Converted<Option<Doubles>> {
+        let c = match list.iter().find(|(n, _)| *n == name) {
+            Some((_, c)) if !c.is_null() => c,
+            _ => return Ok(None),
+        };
+        Doubles::try_from(c.clone())
+            .map(Some)
+            .map_err(|_| ConversionError::new(name, "must be a numeric vector").found(&c))
+    };
+
+    match (col("x")?, col("y")?) {
+        (Some(px), Some(py)) => coordinates(&px, &py, col("z")?.as_ref(), sr),
+        _ => {
+            let msg = "must be an `sfc` of points, WKB, geoarrow points, or coordinates";
+            Err(ConversionError::new(arg, msg).found(&x))
+        }
+    }
+}
+
+// The 1-based rows of points that are empty or missing a coordinate
+pub fn empty_points(x: &[Option<EsriPoint>]) -> Vec<i32> {
+    x.iter()
+        .enumerate()
+        .filter(|(_, p)| p.is_none())
+        .map(|(i, _)| i as i32 + 1)
+        .collect()
+}
+
+// Checks that a column of addresses has one element per row
+pub fn check_len(arg: &str, len: usize, n: usize) -> Converted<()> {
+    if len != n {
+        let msg = format!("must have {n} element{}", if n == 1 { "" } else { "s" });
+        return Err(ConversionError {
+            value: Some(format!("{len}")),
+            ..ConversionError::new(arg, msg)
+        });
+    }
+    Ok(())
+}
+
+// Converts the caller supplied key of every row into strings so that
+// integer and character keys can be compared. `NULL` has no keys.
+pub fn row_keys(arg: &str, x: &Robj, n: usize) -> Converted<Option<Vec<String>>> {
+    if x.is_null() {
+        return Ok(None);
+    }
+
+    let keys = if let Some(v) = x.as_integer_slice() {
+        v.iter().map(|i| i.to_string()).collect::<Vec<_>>()
+    } else if let Some(v) = x.as_real_slice() {
+        v.iter().map(|f| f.to_string()).collect()
+    } else if let Some(v) = x.as_str_iter() {
+        v.map(String::from).collect()
+    } else {
+        let msg = "must be an integer or character vector";
+        return Err(ConversionError::new(arg, msg).found(x));
+    };
+
+    check_len(arg, keys.len(), n)?;
+    Ok(Some(keys))
+}
+
+// Creates an `sfg` POINT. Missing coordinates are NA
+pub fn sfg_point(x: Option<f64>, y: Option<f64>) -> Robj {
+    let mut res = Doubles::from_values([Rfloat::from(x), Rfloat::from(y)]).into_robj();
+    let _ = res.set_class(&["XY", "POINT", "sfg"]);
+    res
+}
+
+// Creates a data.frame from rows of a struct
+pub fn dataframe<T>(rows: Vec<T>) -> Robj
+where
+    Vec<T>: IntoDataFrameRow<T>,
+{
+    match rows.into_dataframe() {
+        Ok(df) => df.as_robj().clone(),
+        Err(e) => throw_r_error(format!("Failed to create data.frame: {e}")),
+    }
+}
+
+// Serializes a value into an R object. `NULL` is returned if that fails
+pub fn serialize<T: Serialize>(x: &T) -> Robj {
+    extendr_api::serializer::to_robj(x).unwrap_or_else(|_| ().into_robj())
+}
+
+// Appends a named element to a list. The row names, class, and decoding
+// problems of a data.frame are kept so that a column can be added to it.
+pub fn push_element(x: Robj, name: &str, value: Robj) -> Robj {
+    let list = match List::try_from(&x) {
+        Ok(l) => l,
+        Err(_) => return x,
+    };
+
+    let mut names = match list.names() {
+        Some(nms) => nms.map(String::from).collect::<Vec<_>>(),
+        None => vec![String::new(); list.len()],
+    };
+    let mut values = list.values().collect::<Vec<_>>();
+    names.push(name.to_string());
+    values.push(value);
+
+    let mut res = match List::from_names_and_values(names, values) {
+        Ok(l) => l.into_robj(),
+        Err(e) => throw_r_error(format!("Failed to add `{name}`: {e}")),
+    };
+
+    for attr in ["row.names", "class", "problems"] {
+        if let Some(a) = x.get_attrib(attr) {
+            let _ = res.set_attrib(attr, a);
+        }
+    }
+    res
+}
@@ -0,0 +1,180 @@
+use crate::retry::Attempt;
+use crate::transport::Reply;
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// The error object that is returned by ArcGIS services
+// often with an HTTP status of 200
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ErrorCode {
+    pub code: i32,
+    #[serde(rename = "extendedCode")]
+    pub extended_code: Option<i32>,
+    pub message: Option<String>,
+    pub details: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ErrorMsg {
+    pub error: ErrorCode,
+}
+
+// Where an error came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    // an error body returned by the service
+    Service,
+    // a response with an unsuccessful HTTP status
+    Http,
+    // a request that never received a response
+    Connection,
+    // a response that did not have the expected shape
+    Parse,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Service => "service",
+            ErrorKind::Http => "http",
+            ErrorKind::Connection => "connection",
+            ErrorKind::Parse => "parse",
+        }
+    }
+}
+
+// An error from any endpoint. These are returned to R instead of
+// being printed so that they can be handled programmatically.
+#[derive(Debug, Clone)]
+pub struct GeocodeError {
+    pub kind: ErrorKind,
+    pub code: Option<i32>,
+    pub extended_code: Option<i32>,
+    pub message: String,
+    pub details: Vec<String>,
+    // identifies the request, chunk, or row that the error belongs to
+    pub request: Option<i32>,
+}
+
+impl From<ErrorCode> for GeocodeError {
+    fn from(err: ErrorCode) -> Self {
+        GeocodeError {
+            kind: ErrorKind::Service,
+            code: Some(err.code),
+            extended_code: err.extended_code,
+            message: err.message.unwrap_or_default(),
+            details: err.details.unwrap_or_default(),
+            request: None,
+        }
+    }
+}
+
+impl GeocodeError {
+    // A response that could not be parsed. If it is an ESRI error body
+    // that error is used, otherwise `message` describes the mismatch
+    pub fn shape(body: &str, message: String) -> Self {
+        match serde_json::from_str::<ErrorMsg>(body) {
+            Ok(e) => e.error.into(),
+            Err(_) => GeocodeError {
+                kind: ErrorKind::Parse,
+                code: None,
+                extended_code: None,
+                message,
+                details: vec![],
+                request: None,
+            },
+        }
+    }
+
+    // A response that failed to deserialize
+    pub fn from_body(body: &str, err: serde_json::Error) -> Self {
+        GeocodeError::shape(body, format!("Failed to parse response: {err}"))
+    }
+
+    // Describes the final failed attempt of a request
+    pub fn from_attempt(attempt: &Attempt) -> Self {
+        let kind = match (attempt.code, attempt.status) {
+            (Some(_), _) => ErrorKind::Service,
+            (None, Some(_)) => ErrorKind::Http,
+            (None, None) => ErrorKind::Connection,
+        };
+
+        GeocodeError {
+            kind,
+            code: attempt.code.or(attempt.status.map(|s| s as i32)),
+            extended_code: attempt.extended_code,
+            message: attempt.message.clone(),
+            details: vec![],
+            request: None,
+        }
+    }
+
+    // The error of a request that did not succeed.
+    // The ESRI error body is preferred as it includes the details
+    pub fn from_reply(reply: &Reply) -> Option<Self> {
+        if reply.succeeded {
+            return None;
+        }
+
+        let from_body = reply
+            .body
+            .as_deref()
+            .and_then(|b| serde_json::from_str::<ErrorMsg>(b).ok())
+            .map(|e| GeocodeError::from(e.error));
+
+        from_body.or_else(|| reply.attempts.last().map(GeocodeError::from_attempt))
+    }
+
+    pub fn with_request(mut self, request: i32) -> Self {
+        self.request = Some(request);
+        self
+    }
+
+    // A single error with the class `arcgisgeocode_error`.
+    // This is returned by the parsers in place of their results
+    pub fn into_robj(self) -> Robj {
+        let mut res = errors_as_robj(&[self], "request");
+        let _ = res.set_class(&["arcgisgeocode_error"]);
+        res
+    }
+}
+
+// Creates a list of the fields of each error which is turned into a
+// data.frame in R. `id` is the name used for the request identifier
+pub fn errors_as_robj(errors: &[GeocodeError], id: &str) -> Robj {
+    let request = errors
+        .iter()
+        .map(|e| Rint::from(e.request))
+        .collect::<Integers>();
+    let kind = errors.iter().map(|e| e.kind.as_str()).collect::<Strings>();
+    let code = errors
+        .iter()
+        .map(|e| Rint::from(e.code))
+        .collect::<Integers>();
+    let extended_code = errors
+        .iter()
+        .map(|e| Rint::from(e.extended_code))
+        .collect::<Integers>();
+    let message = errors
+        .iter()
+        .map(|e| e.message.as_str())
+        .collect::<Strings>();
+    let details = errors
+        .iter()
+        .map(|e| e.details.iter().map(|d| d.as_str()).collect::<Strings>())
+        .collect::<List>();
+
+    List::from_names_and_values(
+        [id, "kind", "code", "extended_code", "message", "details"],
+        [
+            request.into_robj(),
+            kind.into_robj(),
+            code.into_robj(),
+            extended_code.into_robj(),
+            message.into_robj(),
+            details.into_robj(),
+        ],
+    )
+    .map(|l| l.into_robj())
+    .unwrap_or_else(|_| ().into_robj())
+}
@@ -0,0 +1,303 @@
+use crate::arrow::{ArrowArray, ArrowSchema};
+use crate::convert::{self, ConversionError, Converted};
+use crate::wkb::{self, Coords};
+use extendr_api::prelude::*;
+use serde_esri::{geometry::EsriPoint, spatial_reference::SpatialReference};
+use serde_json::Value;
+use std::ffi::{c_char, CStr};
+
+fn c_str(x: *const c_char) -> String {
+    if x.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(x) }.to_string_lossy().into_owned()
+}
+
+// Reads the key-value pairs of schema metadata. These are stored as an
+// int32 number of pairs followed by the length and bytes of each key
+// and value.
+fn metadata(schema: &ArrowSchema) -> Vec<(String, String)> {
+    if schema.metadata.is_null() {
+        return vec![];
+    }
+
+    unsafe fn read_i32(p: &mut *const u8) -> i32 {
+        let v = std::ptr::read_unaligned(*p as *const i32);
+        *p = p.add(4);
+        v
+    }
+
+    unsafe fn read_string(p: &mut *const u8) -> String {
+        let len = read_i32(p).max(0) as usize;
+        let bytes = std::slice::from_raw_parts(*p, len);
+        *p = p.add(len);
+        String::from_utf8_lossy(bytes).into_owned()
+    }
+
+    unsafe {
+        let mut p = schema.metadata as *const u8;
+        let n = read_i32(&mut p);
+        (0..n)
+            .map(|_| {
+                let key = read_string(&mut p);
+                let value = read_string(&mut p);
+                (key, value)
+            })
+            .collect()
+    }
+}
+
+// A column of an Arrow array alongside its schema
+#[derive(Clone, Copy)]
+struct Column<'a> {
+    schema: &'a ArrowSchema,
+    array: &'a ArrowArray,
+}
+
+impl<'a> Column<'a> {
+    fn format(&self) -> String {
+        c_str(self.schema.format)
+    }
+
+    fn name(&self) -> String {
+        c_str(self.schema.name)
+    }
+
+    fn len(&self) -> usize {
+        self.array.length.max(0) as usize
+    }
+
+    fn children(&self) -> Vec<Column<'a>> {
+        let n = self.schema.n_children.min(self.array.n_children).max(0) as usize;
+        (0..n)
+            .filter_map(|i| unsafe {
+                let schema = (*self.schema.children.add(i)).as_ref()?;
+                let array = (*self.array.children.add(i)).as_ref()?;
+                Some(Column { schema, array })
+            })
+            .collect()
+    }
+
+    fn buffer<T>(&self, i: usize) -> *const T {
+        if i as i64 >= self.array.n_buffers || self.array.buffers.is_null() {
+            return std::ptr::null();
+        }
+        unsafe { *self.array.buffers.add(i) as *const T }
+    }
+
+    // Whether the element at `i` is not null. `i` does not include the
+    // offset of the array.
+    fn is_valid(&self, i: usize) -> bool {
+        let validity = self.buffer::<u8>(0);
+        if validity.is_null() {
+            return true;
+        }
+        let j = self.array.offset as usize + i;
+        unsafe { *validity.add(j / 8) & (1 << (j % 8)) != 0 }
+    }
+
+    // The value of a float64 column. Nulls and NaN are `None`
+    fn f64_at(&self, i: usize) -> Option<f64> {
+        let values = self.buffer::<f64>(1);
+        if values.is_null() || !self.is_valid(i) {
+            return None;
+        }
+        let v = unsafe { *values.add(self.array.offset as usize + i) };
+        (!v.is_nan()).then_some(v)
+    }
+
+    // The bytes of a binary or large binary column
+    fn bytes_at(&self, i: usize, large: bool) -> Option<&'a [u8]> {
+        let data = self.buffer::<u8>(2);
+        if data.is_null() || !self.is_valid(i) {
+            return None;
+        }
+        let j = self.array.offset as usize + i;
+        let (start, end) = unsafe {
+            if large {
+                let offsets = self.buffer::<i64>(1);
+                (*offsets.add(j), *offsets.add(j + 1))
+            } else {
+                let offsets = self.buffer::<i32>(1);
+                (*offsets.add(j) as i64, *offsets.add(j + 1) as i64)
+            }
+        };
+        let len = (end - start).max(0) as usize;
+        Some(unsafe { std::slice::from_raw_parts(data.add(start as usize), len) })
+    }
+}
+
+// The dimension names of geoarrow coordinates
+fn dims_of(name: &str, n: usize) -> Vec<char> {
+    match name {
+        "xy" | "xyz" | "xym" | "xyzm" => name.chars().collect(),
+        _ => "xyzm".chars().take(n.min(4)).collect(),
+    }
+}
+
+fn coords_from(dims: &[char], value: impl Fn(usize) -> Option<f64>) -> Option<Coords> {
+    let get = |d: char| dims.iter().position(|c| *c == d).and_then(&value);
+    Some(Coords {
+        x: get('x')?,
+        y: get('y')?,
+        z: get('z'),
+        m: get('m'),
+    })
+}
+
+// Finds the WKID of a geoarrow `crs`. This is either a PROJJSON object or
+// a string such as `"EPSG:4326"`, `"OGC:CRS84"`, or serialized PROJJSON.
+fn crs_wkid(crs: &Value) -> Option<i32> {
+    match crs {
+        Value::String(s) => {
+            let s = s.trim();
+            if s.eq_ignore_ascii_case("OGC:CRS84") {
+                return Some(4326);
+            }
+            match s.strip_prefix("EPSG:") {
+                Some(code) => code.parse().ok(),
+                None => serde_json::from_str::<Value>(s)
+                    .ok()
+                    .filter(|v| v.is_object())
+                    .and_then(|v| crs_wkid(&v)),
+            }
+        }
+        Value::Object(o) => {
+            let id = o.get("id")?;
+            let authority = id.get("authority")?.as_str()?;
+            let code = id.get("code")?;
+            match authority {
+                "EPSG" => code
+                    .as_i64()
+                    .or_else(|| code.as_str()?.parse().ok())
+                    .map(|c| c as i32),
+                "OGC" if code.as_str() == Some("CRS84") => Some(4326),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+fn wkid_sr(wkid: i32) -> Option<SpatialReference> {
+    Some(SpatialReference {
+        wkid: Some(wkid.try_into().ok()?),
+        latest_wkid: None,
+        vcs_wkid: None,
+        latest_vcs_wkid: None,
+        wkt: None,
+    })
+}
+
+// Converts a `nanoarrow_array` of geoarrow points or WKB. The spatial
+// reference is read from the `crs` of the extension metadata and `sr` is
+// used when there is none.
+pub fn points(
+    arg: &str,
+    x: &Robj,
+    sr: Option<&SpatialReference>,
+) -> Converted<Vec<Option<EsriPoint>>> {
+    if !x.inherits("nanoarrow_array") || x.rtype() != Rtype::ExternalPtr {
+        return Err(ConversionError::new(arg, "must be a `nanoarrow_array`").found(x));
+    }
+
+    // nanoarrow stores the schema of an array as the tag of its pointer
+    let (array, schema) = unsafe {
+        let schema = x.external_ptr_tag();
+        let array = x.external_ptr_addr::<ArrowArray>().as_ref();
+        let schema = if schema.rtype() == Rtype::ExternalPtr {
+            schema.external_ptr_addr::<ArrowSchema>().as_ref()
+        } else {
+            None
+        };
+        (array, schema)
+    };
+
+    let col = match (array, schema) {
+        (Some(a), Some(s)) if a.release.is_some() && s.release.is_some() => Column {
+            schema: s,
+            array: a,
+        },
+        _ => {
+            let msg = "must be a valid `nanoarrow_array` with a schema";
+            return Err(ConversionError::new(arg, msg));
+        }
+    };
+
+    let meta = metadata(col.schema);
+    let get = |k: &str| {
+        meta.iter()
+            .find(|(key, _)| key == k)
+            .map(|(_, v)| v.as_str())
+    };
+    let extension = get("ARROW:extension:name").unwrap_or_default();
+
+    let sr = get("ARROW:extension:metadata")
+        .and_then(|m| serde_json::from_str::<Value>(m).ok())
+        .and_then(|m| m.get("crs").and_then(crs_wkid))
+        .and_then(wkid_sr)
+        .or_else(|| sr.cloned())
+        .ok_or_else(|| {
+            let msg = "must have a `crs` when no spatial reference is provided";
+            ConversionError::new(arg, msg)
+        })?;
+
+    let n = col.len();
+    let format = col.format();
+
+    let coords: Vec<Option<Coords>> = match (extension, format.as_str()) {
+        ("geoarrow.point", "+s") => {
+            let children = col.children();
+            let names = children.iter().map(|c| c.name()).collect::<String>();
+            let dims = dims_of(&names, children.len());
+            let offset = col.array.offset as usize;
+            (0..n)
+                .map(|i| {
+                    if !col.is_valid(i) {
+                        return None;
+                    }
+                    coords_from(&dims, |d| children.get(d)?.f64_at(offset + i))
+                })
+                .collect()
+        }
+        ("geoarrow.point", f) if f.starts_with("+w:") => {
+            let values = match col.children().first() {
+                Some(v) => *v,
+                None => return Err(ConversionError::new(arg, "has no coordinates")),
+            };
+            let size = f[3..].parse::<usize>().unwrap_or(0);
+            let dims = dims_of(&values.name(), size);
+            let offset = col.array.offset as usize;
+            (0..n)
+                .map(|i| {
+                    if !col.is_valid(i) {
+                        return None;
+                    }
+                    coords_from(&dims, |d| values.f64_at((offset + i) * size + d))
+                })
+                .collect()
+        }
+        ("geoarrow.wkb", "z" | "Z") => {
+            let large = format == "Z";
+            (0..n)
+                .map(|i| match col.bytes_at(i, large) {
+                    Some(b) => wkb::parse_point(b).map_err(|e| ConversionError::new(arg, e).at(i)),
+                    None => Ok(None),
+                })
+                .collect::<Converted<_>>()?
+        }
+        ("", _) => {
+            let msg = format!("must have a geoarrow extension type. Found format `{format}`");
+            return Err(ConversionError::new(arg, msg));
+        }
+        (ext, _) => {
+            let msg = format!("must be `geoarrow.point` or `geoarrow.wkb` not `{ext}`");
+            return Err(ConversionError::new(arg, msg));
+        }
+    };
+
+    Ok(coords
+        .into_iter()
+        .map(|c| c.map(|c| convert::esri_point(c, &sr)))
+        .collect())
+}
@@ -0,0 +1,198 @@
+use crate::error::ErrorMsg;
+use crate::find_candidates::ATTRIBUTE_FIELDS;
+use crate::schema::{Field, Schema};
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_esri::spatial_reference::SpatialReference;
+use serde_json::{Map, Value};
+use serde_with::{serde_as, DisplayFromStr, PickFirst};
+
+// Used when a locator does not report its batch sizes.
+// These are the defaults of ArcGIS Enterprise locators.
+const DEFAULT_MAX_BATCH_SIZE: u32 = 1000;
+const DEFAULT_SUGGESTED_BATCH_SIZE: u32 = 150;
+
+// Properties of the locator. Enterprise locators often report these as
+// strings so both numbers and strings are accepted.
+#[serde_as]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LocatorProperties {
+    #[serde(rename = "MaxBatchSize", default)]
+    #[serde_as(as = "Option<PickFirst<(_, DisplayFromStr)>>")]
+    pub max_batch_size: Option<u32>,
+    #[serde(rename = "SuggestedBatchSize", default)]
+    #[serde_as(as = "Option<PickFirst<(_, DisplayFromStr)>>")]
+    pub suggested_batch_size: Option<u32>,
+    #[serde(rename = "MaxResultSize", default)]
+    #[serde_as(as = "Option<PickFirst<(_, DisplayFromStr)>>")]
+    pub max_result_size: Option<u32>,
+    #[serde(rename = "LoadBalancerTimeOut", default)]
+    #[serde_as(as = "Option<PickFirst<(_, DisplayFromStr)>>")]
+    pub load_balancer_timeout: Option<f64>,
+    // every other property is kept as is
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+// The resource of a GeocodeServer as returned by `{url}?f=json`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct GeocodeServer {
+    pub current_version: Option<f64>,
+    pub service_description: Option<String>,
+    pub address_fields: Vec<Field>,
+    pub single_line_address_field: Option<Field>,
+    pub candidate_fields: Vec<Field>,
+    pub spatial_reference: Option<SpatialReference>,
+    pub locator_properties: LocatorProperties,
+    // a comma separated list such as `Geocode,ReverseGeocode,Suggest`
+    pub capabilities: String,
+    pub countries: Vec<String>,
+}
+
+impl GeocodeServer {
+    // Parses and validates the service description
+    pub fn from_json(x: &str) -> std::result::Result<Self, String> {
+        if let Ok(e) = serde_json::from_str::<ErrorMsg>(x) {
+            let msg = e.error.message.unwrap_or_default();
+            return Err(format!("service returned error {}: {msg}", e.error.code));
+        }
+
+        let server = serde_json::from_str::<GeocodeServer>(x)
+            .map_err(|e| format!("failed to parse service description: {e}"))?;
+
+        server.validate()?;
+        Ok(server)
+    }
+
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        let props = &self.locator_properties;
+
+        if props.max_batch_size == Some(0) {
+            return Err(String::from("`MaxBatchSize` must be at least 1"));
+        }
+
+        if props.suggested_batch_size == Some(0) {
+            return Err(String::from("`SuggestedBatchSize` must be at least 1"));
+        }
+
+        for fields in [&self.address_fields, &self.candidate_fields] {
+            for (i, f) in fields.iter().enumerate() {
+                if f.name.is_empty() {
+                    return Err(format!("field {} does not have a name", i + 1));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // The lower case capabilities of the locator
+    pub fn capabilities(&self) -> Vec<String> {
+        self.capabilities
+            .split(',')
+            .map(|c| c.trim().to_lowercase())
+            .filter(|c| !c.is_empty())
+            .collect()
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities()
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(capability))
+    }
+
+    pub fn max_batch_size(&self) -> u32 {
+        self.locator_properties
+            .max_batch_size
+            .unwrap_or(DEFAULT_MAX_BATCH_SIZE)
+    }
+
+    // The suggested batch size is never larger than the maximum
+    pub fn suggested_batch_size(&self) -> u32 {
+        self.locator_properties
+            .suggested_batch_size
+            .unwrap_or(DEFAULT_SUGGESTED_BATCH_SIZE)
+            .min(self.max_batch_size())
+    }
+
+    // The number of addresses per /geocodeAddresses request when it is not
+    // provided. This splits the difference between the suggested and the
+    // maximum batch size.
+    pub fn batch_size(&self) -> u32 {
+        (self.suggested_batch_size() + self.max_batch_size()) / 2
+    }
+
+    // The candidate fields that are not returned by the ArcGIS World
+    // Geocoder. Results of locators with custom fields are decoded using
+    // their `candidateFields` instead of the known attributes.
+    pub fn custom_fields(&self) -> Vec<&Field> {
+        self.candidate_fields
+            .iter()
+            .filter(|f| !ATTRIBUTE_FIELDS.iter().any(|a| a.name == f.name))
+            .collect()
+    }
+
+    pub fn max_result_size(&self) -> Option<u32> {
+        self.locator_properties.max_result_size
+    }
+
+    pub fn load_balancer_timeout(&self) -> Option<f64> {
+        self.locator_properties.load_balancer_timeout
+    }
+
+    pub fn single_line_field(&self) -> Option<&str> {
+        self.single_line_address_field
+            .as_ref()
+            .map(|f| f.name.as_str())
+    }
+
+    pub fn schema(&self) -> Schema {
+        Schema::from_fields(&self.candidate_fields)
+    }
+
+    pub fn wkid(&self) -> Option<i32> {
+        let sr = self.spatial_reference.as_ref()?;
+        sr.latest_wkid.or(sr.wkid).map(|w| w as i32)
+    }
+}
+
+fn field_names<'a>(x: impl IntoIterator<Item = &'a Field>) -> Strings {
+    x.into_iter().map(|f| f.name.as_str()).collect::<Strings>()
+}
+
+// Parses the service description of a GeocodeServer. The properties that
+// the other functions rely on are returned with their defaults filled in.
+#[extendr]
+fn geocode_server_metadata(json: &str) -> Robj {
+    let server = match GeocodeServer::from_json(json) {
+        Ok(s) => s,
+        Err(e) => throw_r_error(format!("Invalid GeocodeServer: {e}")),
+    };
+
+    let as_rint = |x: Option<u32>| Rint::from(x.and_then(|xi| i32::try_from(xi).ok()));
+
+    list!(
+        capabilities = server.capabilities(),
+        geocode = server.supports("geocode"),
+        reverse_geocode = server.supports("reversegeocode"),
+        suggest = server.supports("suggest"),
+        max_batch_size = as_rint(Some(server.max_batch_size())),
+        suggested_batch_size = as_rint(Some(server.suggested_batch_size())),
+        batch_size = as_rint(Some(server.batch_size())),
+        max_result_size = as_rint(server.max_result_size()),
+        load_balancer_timeout = Rfloat::from(server.load_balancer_timeout()),
+        single_line_field = server.single_line_field(),
+        address_fields = field_names(&server.address_fields),
+        candidate_fields = field_names(&server.schema().fields),
+        custom_fields = field_names(server.custom_fields()),
+        countries = server.countries.clone(),
+        wkid = Rint::from(server.wkid())
+    )
+    .into_robj()
+}
+
+extendr_module! {
+    mod geocode_server;
+    fn geocode_server_metadata;
+}
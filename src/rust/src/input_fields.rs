@@ -0,0 +1,121 @@
+use crate::convert::{ConversionError, Converted};
+use extendr_api::prelude::*;
+
+// The input fields of the World Geocoder. These are used when a locator
+// does not describe its `addressFields`.
+const WORLD_ADDRESS_FIELDS: [&str; 10] = [
+    "Address",
+    "Address2",
+    "Address3",
+    "Neighborhood",
+    "City",
+    "Subregion",
+    "Region",
+    "Postal",
+    "PostalExt",
+    "CountryCode",
+];
+const WORLD_SINGLE_LINE_FIELD: &str = "SingleLine";
+
+// Names are compared ignoring case and every character that is not a
+// letter or digit so that `postal_ext` matches `PostalExt`
+fn normalize(x: &str) -> String {
+    x.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+// The fields a locator accepts in the records of /geocodeAddresses
+#[derive(Debug, Clone)]
+pub struct InputFields {
+    pub address_fields: Vec<String>,
+    pub single_line_field: Option<String>,
+}
+
+impl Default for InputFields {
+    fn default() -> Self {
+        InputFields {
+            address_fields: WORLD_ADDRESS_FIELDS.iter().map(|f| f.to_string()).collect(),
+            single_line_field: Some(WORLD_SINGLE_LINE_FIELD.to_string()),
+        }
+    }
+}
+
+impl InputFields {
+    // Reads the `address_fields` and `single_line_field` of the output of
+    // `geocode_server_metadata()`. `NULL` uses the World Geocoder fields.
+    pub fn from_robj(x: &Robj) -> Converted<Self> {
+        if x.is_null() {
+            return Ok(InputFields::default());
+        }
+
+        let list = List::try_from(x.clone())
+            .map_err(|_| ConversionError::new("fields", "must be a list").found(x))?;
+
+        let get = |name: &str| list.iter().find(|(n, _)| *n == name).map(|(_, v)| v);
+
+        let address_fields = match get("address_fields") {
+            Some(f) if !f.is_null() => Strings::try_from(f.clone())
+                .map_err(|_| {
+                    let msg = "must contain a character vector of `address_fields`";
+                    ConversionError::new("fields", msg).found(&f)
+                })?
+                .iter()
+                .filter(|f| !f.is_na())
+                .map(|f| f.to_string())
+                .collect::<Vec<_>>(),
+            _ => vec![],
+        };
+
+        // a missing, NA, or empty single line field is absent
+        let single_line_field = get("single_line_field")
+            .and_then(|f| Strings::try_from(f).ok())
+            .and_then(|f| f.iter().next())
+            .filter(|f| !f.is_na())
+            .map(|f| f.to_string())
+            .filter(|f| !f.is_empty());
+
+        if address_fields.is_empty() && single_line_field.is_none() {
+            return Ok(InputFields::default());
+        }
+
+        // locators that do not report a single line field use the default
+        Ok(InputFields {
+            address_fields,
+            single_line_field: single_line_field
+                .or_else(|| Some(WORLD_SINGLE_LINE_FIELD.to_string())),
+        })
+    }
+
+    // Finds the locator field of an input column. `single_line` is the
+    // single line field of the locator and every other column must match
+    // one of its `addressFields`.
+    pub fn resolve(&self, column: &str) -> Converted<String> {
+        let key = normalize(column);
+
+        if key == "singleline" {
+            return self
+                .single_line_field
+                .clone()
+                .ok_or_else(|| ConversionError::new(column, "is not supported by the locator"));
+        }
+
+        let field = self
+            .address_fields
+            .iter()
+            .chain(self.single_line_field.iter())
+            .find(|f| normalize(f) == key);
+
+        match field {
+            Some(f) => Ok(f.clone()),
+            None => {
+                let msg = format!(
+                    "is not an address field of the locator. Expected one of: {}",
+                    self.address_fields.join(", ")
+                );
+                Err(ConversionError::new(column, msg))
+            }
+        }
+    }
+}
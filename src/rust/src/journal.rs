@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+
+// Identifies the job that a journal belongs to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Manifest {
+    n: usize,
+    fingerprint: String,
+}
+
+// A completed chunk. `object_ids` are the inclusive ranges of the
+// ObjectIDs that were sent and `response` is the reply of the service.
+// `row_keys` are the caller supplied keys of the rows in the same order
+// as the ObjectIDs when the job has them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub object_ids: Vec<[i32; 2]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub row_keys: Option<Vec<String>>,
+    pub response: Value,
+}
+
+impl JournalEntry {
+    // The 0-based rows that this chunk contains. ObjectIDs are 1-based
+    // rows so a range outside of `1..=n` means the journal is corrupt.
+    pub fn rows(&self, n: usize) -> Result<Vec<usize>> {
+        let mut rows = Vec::new();
+        for &[start, end] in self.object_ids.iter() {
+            let first = usize::try_from(start).ok().and_then(|s| s.checked_sub(1));
+            let last = usize::try_from(end).ok().and_then(|e| e.checked_sub(1));
+
+            match (first, last) {
+                (Some(first), Some(last)) if first <= last && last < n => rows.extend(first..=last),
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid ObjectID range `[{start}, {end}]` for {n} addresses"),
+                    ))
+                }
+            }
+        }
+        Ok(rows)
+    }
+}
+
+// A directory of completed chunks of a batch geocoding job.
+// Each chunk is written to its own file as soon as it completes so that
+// an interrupted job can be resumed without sending those chunks again.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    dir: PathBuf,
+}
+
+// 64-bit FNV-1a hash. The fingerprint needs to be stable across sessions
+// which is not guaranteed by the hasher in the standard library.
+#[derive(Debug, Clone)]
+pub struct Fingerprint(u64);
+
+impl Default for Fingerprint {
+    fn default() -> Self {
+        Fingerprint(0xcbf29ce484222325)
+    }
+}
+
+impl Fingerprint {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+// Compresses sorted rows into inclusive ranges of ObjectIDs
+fn object_id_ranges(rows: &[usize]) -> Vec<[i32; 2]> {
+    let mut ranges: Vec<[i32; 2]> = Vec::new();
+    for &row in rows {
+        let oid = row as i32 + 1;
+        match ranges.last_mut() {
+            Some(r) if r[1] + 1 == oid => r[1] = oid,
+            _ => ranges.push([oid, oid]),
+        }
+    }
+    ranges
+}
+
+impl Journal {
+    // Opens a journal, creating the directory if needed. A journal can only
+    // be used to resume the job that it was created for.
+    pub fn open(dir: &str, n: usize, fingerprint: String) -> Result<Self> {
+        let dir = PathBuf::from(dir);
+        fs::create_dir_all(&dir)?;
+
+        let manifest = Manifest { n, fingerprint };
+        let manifest_path = dir.join("manifest.json");
+
+        if manifest_path.exists() {
+            let existing = serde_json::from_str::<Manifest>(&fs::read_to_string(&manifest_path)?)?;
+            if existing != manifest {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "journal at `{}` was created for a different set of addresses",
+                        dir.display()
+                    ),
+                ));
+            }
+        } else {
+            fs::write(&manifest_path, serde_json::to_string(&manifest)?)?;
+        }
+
+        Ok(Journal { dir })
+    }
+
+    // Reads every completed chunk
+    pub fn completed(&self) -> Result<Vec<JournalEntry>> {
+        let mut entries = Vec::new();
+        for file in fs::read_dir(&self.dir)? {
+            let path = file?.path();
+            let is_chunk = path
+                .file_name()
+                .and_then(|f| f.to_str())
+                .map_or(false, |f| f.starts_with("chunk-") && f.ends_with(".json"));
+
+            if !is_chunk {
+                continue;
+            }
+
+            // chunks that were only partially written are sent again
+            if let Ok(entry) = serde_json::from_str::<JournalEntry>(&fs::read_to_string(&path)?) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    // Records a completed chunk. The file is written to a temporary
+    // location first so that an interruption never leaves a partial chunk.
+    // `keys` are the row keys of the job, if any.
+    pub fn record(&self, rows: &[usize], body: &str, keys: Option<&[String]>) -> Result<()> {
+        let object_ids = object_id_ranges(rows);
+        let (first, last) = match (object_ids.first(), object_ids.last()) {
+            (Some(f), Some(l)) => (f[0], l[1]),
+            _ => return Ok(()),
+        };
+
+        let entry = JournalEntry {
+            object_ids,
+            row_keys: keys.map(|k| rows.iter().map(|&i| k[i].clone()).collect()),
+            response: serde_json::from_str(body)?,
+        };
+
+        let fname = format!("chunk-{first}-{last}.json");
+        let tmp = self.dir.join(format!("{fname}.tmp"));
+        fs::write(&tmp, serde_json::to_string(&entry)?)?;
+        fs::rename(tmp, self.dir.join(fname))
+    }
+}
@@ -1,146 +1,218 @@
-use std::collections::HashMap;
-
+use crate::columns;
+use crate::convert;
+use crate::error::GeocodeError;
+use crate::schema::{Decoder, Schema};
 use extendr_api::prelude::*;
-use extendr_api::serializer::to_robj;
 use serde_json::{de::from_str, Value};
 
-// Takes a dataframe and creates a hashmap of column names and types
-// these are used to match and insert into the elements of a dataframe
-fn make_df_type_map(x: &List) -> HashMap<String, Rtype> {
-    let mut map = HashMap::new();
-    x.iter().for_each(|(key, val)| {
-        map.insert(String::from(key), val.rtype());
-    });
-    map
+// Creates an `sfg` POINT from an ESRI point. Missing coordinates are NA
+fn point_as_sfg(x: Option<&Value>) -> Robj {
+    let coord = |k: &str| x.and_then(|xi| xi.get(k)).and_then(|v| v.as_f64());
+    convert::sfg_point(coord("x"), coord("y"))
+}
+
+fn sr_as_robj(x: &Value) -> Robj {
+    convert::serialize(&x.get("spatialReference"))
 }
 
-// Function to insert into a data.frame. Requires lots of matching a bit messy
-fn insert_into_df(x: &mut List, key: &str, rtype: &Rtype, val: Value, i: usize) {
-    match rtype {
-        Rtype::Logicals => {
-            let col = x.dollar(key).unwrap();
-            let mut col_to_insert = Logicals::try_from(col).unwrap();
-            let _ = col_to_insert.set_elt(i, Rbool::from(val.as_bool()));
+// Parses a /geocodeAddresses response of a locator with custom fields.
+// `fields` is a data.frame with the `name` and `type` of each of the
+// `candidateFields` of the locator.
+#[extendr]
+fn parse_custom_location_json_(x: &str, fields: List) -> Robj {
+    let res: Value = match from_str(x) {
+        Ok(r) => r,
+        Err(e) => return GeocodeError::from_body(x, e).into_robj(),
+    };
+
+    let locs = match res.get("locations").and_then(|l| l.as_array()) {
+        Some(l) => l,
+        None => {
+            let msg = String::from("Response does not contain `locations`");
+            return GeocodeError::shape(x, msg).into_robj();
         }
-        Rtype::Integers => {
-            let col = x.dollar(key).unwrap();
-            let mut col_to_insert = Integers::try_from(col).unwrap();
-            let to_insert = match val.as_i64() {
-                Some(v) => Rint::from(v as i32),
-                None => Rint::na(),
-            };
-            let _ = col_to_insert.set_elt(i, to_insert);
+    };
+
+    let mut decoder = Decoder::new(&Schema::from_list(&fields));
+    let mut locations = List::new(locs.len());
+
+    for (i, loc) in locs.iter().enumerate() {
+        decoder.push(loc.get("attributes").and_then(|a| a.as_object()));
+        let _ = locations.set_elt(i, point_as_sfg(loc.get("location")));
+    }
+
+    list!(
+        attributes = decoder.finish(),
+        locations = locations,
+        sr = sr_as_robj(&res)
+    )
+    .into()
+}
+
+// The results of /geocodeAddresses responses of a locator with custom
+// fields with one row per input row. Locations are kept until every
+// response was read and are then decoded using the `candidateFields`.
+pub struct CustomResults {
+    schema: Schema,
+    rows: Vec<Option<Value>>,
+    sr: Option<Value>,
+}
+
+impl CustomResults {
+    // `fields` is a data.frame with the `name` and `type` of each of the
+    // `candidateFields` of the locator
+    pub fn new(fields: &List, n: usize) -> Self {
+        CustomResults {
+            schema: Schema::from_list(fields),
+            rows: vec![None; n],
+            sr: None,
         }
-        Rtype::Doubles => {
-            let col = x.dollar(key).unwrap();
-            let mut col_to_insert = Doubles::try_from(col).unwrap();
-            let _ = col_to_insert.set_elt(i, Rfloat::from(val.as_f64()));
+    }
+
+    pub fn n_results(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_filled(&self, row: usize) -> bool {
+        self.rows.get(row).map_or(false, |r| r.is_some())
+    }
+
+    // The spatial reference of the first response that was read
+    pub fn spatial_reference(&self) -> Option<&Value> {
+        self.sr.as_ref()
+    }
+
+    // Reads every location of a response into the row of its ResultID.
+    // Returns the ResultIDs that are not a row or whose row was already
+    // filled. These are skipped.
+    pub fn read_aligned(&mut self, x: &str) -> Result<Vec<i32>, GeocodeError> {
+        let res: Value = from_str(x).map_err(|e| GeocodeError::from_body(x, e))?;
+
+        let (sr, locs) = match res {
+            Value::Object(mut r) => (r.remove("spatialReference"), r.remove("locations")),
+            _ => (None, None),
+        };
+
+        let locs = match locs {
+            Some(Value::Array(l)) => l,
+            _ => {
+                let msg = String::from("Response does not contain `locations`");
+                return Err(GeocodeError::shape(x, msg));
+            }
+        };
+
+        let ids = locs
+            .iter()
+            .map(|loc| {
+                loc.get("attributes")?
+                    .get("ResultID")?
+                    .as_i64()
+                    .and_then(|id| i32::try_from(id).ok())
+            })
+            .collect::<Vec<_>>();
+
+        let filled = self.rows.iter().map(Option::is_some).collect::<Vec<_>>();
+        let (targets, unplaced) = columns::place(&ids, &filled);
+
+        for (loc, target) in locs.into_iter().zip(targets) {
+            if let Some(row) = target {
+                self.rows[row] = Some(loc);
+            }
         }
-        Rtype::Strings => {
-            let col = x.dollar(key).unwrap();
-            let mut col_to_insert = Strings::try_from(col).unwrap();
-            let to_insert = match val.as_str() {
-                Some(v) => Rstr::from(v),
-                None => Rstr::na(),
-            };
-
-            let _ = col_to_insert.set_elt(i, to_insert);
+
+        if self.sr.is_none() {
+            self.sr = sr;
         }
-        _ => unimplemented!(),
-    };
+
+        Ok(unplaced)
+    }
+
+    // Copies the location of row `from` to row `to`
+    pub fn copy_row(&mut self, from: usize, to: usize) {
+        self.rows[to] = self.rows[from].clone();
+    }
+
+    // A list of the decoded attributes, the locations, and the spatial
+    // reference. The ResultID of each row is its position.
+    pub fn into_robj(self) -> Robj {
+        let mut decoder = Decoder::new(&self.schema);
+        let mut locations = List::new(self.rows.len());
+
+        for (i, row) in self.rows.into_iter().enumerate() {
+            let mut attrs = row
+                .as_ref()
+                .and_then(|r| r.get("attributes"))
+                .and_then(|a| a.as_object())
+                .cloned()
+                .unwrap_or_default();
+            attrs.insert(String::from("ResultID"), Value::from(i + 1));
+
+            decoder.push(Some(&attrs));
+            let point = point_as_sfg(row.as_ref().and_then(|r| r.get("location")));
+            let _ = locations.set_elt(i, point);
+        }
+
+        list!(
+            attributes = decoder.finish(),
+            locations = locations,
+            sr = convert::serialize(&self.sr)
+        )
+        .into()
+    }
 }
 
-// Takes a data.frame (list) that is modified by reference
-// it must be completely pre-allocated otherwise a panic occurs
+// Parses a /findAddressCandidates response of a locator with custom fields.
+// `fields` is a data.frame with the `name` and `type` of each of the
+// `candidateFields` of the locator.
 #[extendr]
-fn parse_custom_location_json_(x: &str, to_fill: List) -> Robj {
-    let col_maps = make_df_type_map(&to_fill);
-    let mut to_fill = to_fill;
+fn parse_custom_candidate_json_(x: &str, fields: List) -> Robj {
+    let res: Value = match from_str(x) {
+        Ok(r) => r,
+        Err(e) => return GeocodeError::from_body(x, e).into_robj(),
+    };
 
-    let mut res: Value = from_str(x).unwrap();
+    let candidates = match res.get("candidates").and_then(|c| c.as_array()) {
+        Some(c) => c,
+        None => {
+            let msg = String::from("Response does not contain `candidates`");
+            return GeocodeError::shape(x, msg).into_robj();
+        }
+    };
 
-    let res = res.as_object_mut();
+    let n = candidates.len();
+    if n == 0 {
+        return ().into_robj();
+    }
 
-    let locs = res.filter(|xi| xi.contains_key("locations"));
+    let mut decoder = Decoder::new(&Schema::from_list(&fields));
+    let mut extents = List::new(n);
+    let mut locations = List::new(n);
 
-    if locs.is_none() {
-        return ().into_robj();
+    for (i, cand) in candidates.iter().enumerate() {
+        decoder.push(cand.get("attributes").and_then(|a| a.as_object()));
+
+        let extent = cand.get("extent");
+        let bound = |k: &str| Rfloat::from(extent.and_then(|e| e.get(k)).and_then(|v| v.as_f64()));
+        let mut extent =
+            Doubles::from_values([bound("xmin"), bound("ymin"), bound("xmax"), bound("ymax")])
+                .into_robj();
+        let _ = extent.set_attrib("names", ["xmin", "ymin", "xmax", "ymax"]);
+
+        let _ = extents.set_elt(i, extent);
+        let _ = locations.set_elt(i, point_as_sfg(cand.get("location")));
     }
-    // rprintln!("Did we find locations?: {:?}", locs.is_some());
-
-    // create bindings to set from inside the scope of the iterator
-    let mut res_locs = ().into_robj();
-    let mut res_sr = ().into_robj();
-    locs.into_iter().for_each(|li| {
-        let sr = li.get("spatialReference");
-        res_sr = match to_robj(&sr) {
-            Ok(r) => r,
-            Err(_) => ().into_robj(),
-        };
 
-        let r = li
-            .get("locations")
-            .unwrap()
-            .as_array()
-            .unwrap()
-            .into_iter()
-            .enumerate()
-            .map(|(i, loc)| {
-                let _r = loc
-                    .get("attributes")
-                    .unwrap()
-                    .as_object()
-                    .into_iter()
-                    .for_each(|lli| {
-                        lli.into_iter().for_each(|li| {
-                            // FIXME this should not be cloned!!!
-                            let key = li.0.as_str();
-                            let val = li.1.clone();
-                            // if this is None then we have an unexpected value
-                            // it is skipped
-                            let ctype = col_maps.get(key);
-                            match ctype {
-                                Some(c) => {
-                                    let _inserted = insert_into_df(&mut to_fill, key, c, val, i);
-                                }
-                                None => (),
-                            };
-                        });
-                    });
-
-                let location_field = loc.get("location");
-
-                match location_field {
-                    Some(lf) => match lf.as_object() {
-                        Some(l) => {
-                            let xi = Rfloat::from(l.get("x").unwrap().as_f64());
-                            let yi = Rfloat::from(l.get("y").unwrap().as_f64());
-                            Doubles::from_values([xi, yi])
-                                .into_robj()
-                                .set_class(&["XY", "POINT", "sfg"])
-                                .unwrap()
-                                .to_owned()
-                        }
-                        None => Doubles::from_values([Rfloat::na(), Rfloat::na()])
-                            .into_robj()
-                            .set_class(&["XY", "POINT", "sfg"])
-                            .unwrap()
-                            .to_owned(),
-                    },
-                    None => Doubles::from_values([Rfloat::na(), Rfloat::na()])
-                        .into_robj()
-                        .set_class(&["XY", "POINT", "sfg"])
-                        .unwrap()
-                        .to_owned(),
-                }
-            })
-            .collect::<List>();
-        res_locs = r.into();
-    });
-    list!(attributes = to_fill, locations = res_locs, sr = res_sr).into()
+    list!(
+        attributes = decoder.finish(),
+        extents = extents,
+        locations = locations,
+        sr = sr_as_robj(&res)
+    )
+    .into()
 }
 
 extendr_module! {
     mod parse_custom_attrs;
     fn parse_custom_location_json_;
+    fn parse_custom_candidate_json_;
 }
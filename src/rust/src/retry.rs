@@ -0,0 +1,173 @@
+use crate::error::ErrorMsg;
+use rand::Rng;
+use std::time::Duration;
+
+// Determines how many times, and how long to wait between,
+// attempts of a single request
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_attempts: u32) -> Self {
+        RetryPolicy {
+            max_attempts: max_attempts.max(1),
+            ..Default::default()
+        }
+    }
+
+    // Exponential backoff with jitter. The delay is drawn uniformly
+    // between half and all of `base_delay * 2^attempt`.
+    // `attempt` starts at 1 for the first request
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = 2_u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(exp).min(self.max_delay);
+        let half = delay / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+
+    // How long to wait before the next attempt. A `Retry-After` from the
+    // service is used when present but never exceeds `max_delay`
+    pub fn delay(&self, attempt: u32, retry_after: Option<Duration>) -> Duration {
+        match retry_after {
+            Some(d) => d.min(self.max_delay),
+            None => self.backoff(attempt),
+        }
+    }
+}
+
+// What should happen after an attempt
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Success,
+    // Retry the request, if the service asked us to wait, how long
+    Transient(Option<Duration>),
+    Fatal,
+}
+
+// Status codes from ArcGIS that indicate that the service is busy
+// or the request timed out. These are worth trying again.
+// Others such as 400 (invalid parameters), 498 (invalid token),
+// and 499 (token required) will never succeed by retrying.
+fn is_transient_code(code: i32) -> bool {
+    matches!(code, 429 | 500 | 502 | 503 | 504)
+}
+
+// Codes that indicate an invalid or missing token
+// or insufficient privileges
+fn is_auth_code(code: i32) -> bool {
+    matches!(code, 401 | 403 | 498 | 499)
+}
+
+// Classifies a response by its HTTP status and then by the body.
+// ArcGIS typically returns an error body with an HTTP status of 200
+// so the `ErrorCode` is used when present.
+pub fn classify(status: u16, retry_after: Option<Duration>, body: &str) -> Verdict {
+    if is_transient_code(status as i32) {
+        return Verdict::Transient(retry_after);
+    }
+
+    if !(200..300).contains(&status) {
+        return Verdict::Fatal;
+    }
+
+    match serde_json::from_str::<ErrorMsg>(body) {
+        Ok(e) => {
+            let err = e.error;
+            let transient =
+                is_transient_code(err.code) || err.extended_code.map_or(false, is_transient_code);
+
+            if transient {
+                Verdict::Transient(None)
+            } else {
+                Verdict::Fatal
+            }
+        }
+        Err(_) => Verdict::Success,
+    }
+}
+
+// Diagnostics for an attempt that did not succeed
+#[derive(Debug, Clone, Default)]
+pub struct Attempt {
+    pub attempt: u32,
+    pub status: Option<u16>,
+    pub code: Option<i32>,
+    pub extended_code: Option<i32>,
+    pub message: String,
+    // how long we waited before trying again
+    pub delay: Option<Duration>,
+}
+
+impl Attempt {
+    // Describes a response using the error body if there is one
+    pub fn from_response(attempt: u32, status: u16, body: &str) -> Self {
+        match serde_json::from_str::<ErrorMsg>(body) {
+            Ok(e) => {
+                let err = e.error;
+                let mut message = err.message.unwrap_or_default();
+                if let Some(details) = err.details.filter(|d| !d.is_empty()) {
+                    message = format!("{message} {}", details.join(" "));
+                }
+                Attempt {
+                    attempt,
+                    status: Some(status),
+                    code: Some(err.code),
+                    extended_code: err.extended_code,
+                    message,
+                    delay: None,
+                }
+            }
+            Err(_) => Attempt {
+                attempt,
+                status: Some(status),
+                message: format!("HTTP status {status}"),
+                ..Default::default()
+            },
+        }
+    }
+
+    // Describes a request that did not receive a response
+    pub fn from_error(attempt: u32, e: &reqwest::Error) -> Self {
+        Attempt {
+            attempt,
+            status: e.status().map(|s| s.as_u16()),
+            message: e.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+// Whether a request was rejected because of its content, for example,
+// when a single malformed record makes the service reject a whole batch.
+// Such a request may succeed when its content is split up.
+pub fn is_rejected(attempt: &Attempt) -> bool {
+    let code = match attempt.code {
+        Some(c) => c,
+        None => match attempt.status {
+            Some(s) => s as i32,
+            None => return false,
+        },
+    };
+
+    (400..500).contains(&code) && !is_transient_code(code) && !is_auth_code(code)
+}
+
+// Timeouts and failed connections are retried, other
+// errors such as invalid urls are not
+pub fn is_transient_error(e: &reqwest::Error) -> bool {
+    e.is_timeout() || e.is_connect()
+}
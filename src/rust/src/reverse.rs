@@ -1,52 +1,14 @@
+use crate::cache::{cache_key, GeocodeCache};
+use crate::cassette::Cassette;
+use crate::columns::{AttributeField, Kind, Results};
+use crate::convert::{self, ConversionError, Converted, OrThrow};
+use crate::error::{errors_as_robj, GeocodeError};
+use crate::retry::RetryPolicy;
+use crate::transport::{attempts_as_robj, endpoint_url, FormBody, Transport};
 use extendr_api::prelude::*;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_esri::{geometry::EsriPoint, spatial_reference::SpatialReference};
-
-// use reqwest::Url;
-// use std::result::Result;
-// use std::sync::Arc;
-
-// pub async fn reverse_geocode_(
-//     service_url: Url,
-//     params: Vec<ReverseGeocodeParams>,
-//     token: Option<String>,
-// ) -> Vec<Result<ReverseGeocodeResponse, reqwest::Error>> {
-//     let url = Arc::new(service_url);
-//     let client = reqwest::Client::new();
-//     let token = Arc::new(token.unwrap_or("".to_string()));
-
-//     let mut tasks = Vec::with_capacity(params.len());
-
-//     // create a task for each param
-//     for param in params {
-//         let task = client
-//             .clone()
-//             .post(url.as_ref().clone())
-//             .query(&[("f", "json")])
-//             .form(&param.as_form_body())
-//             .header("X-Esri-Authorization", token.as_ref().clone())
-//             .send();
-
-//         tasks.push(tokio::spawn(task));
-//     }
-
-//     // create a vector to store the output
-//     let mut outputs = Vec::with_capacity(tasks.len());
-
-//     // capture the output of each task
-//     for task in tasks {
-//         let task_res = task.await.unwrap();
-//         match task_res {
-//             Ok(res) => {
-//                 let res = res.json::<ReverseGeocodeResponse>().await;
-//                 outputs.push(res);
-//             }
-//             Err(e) => outputs.push(Err(e)),
-//         }
-//     }
-
-//     outputs
-// }
+use serde_json::Value;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ReverseGeocodeParams {
@@ -65,64 +27,61 @@ pub struct ReverseGeocodeParams {
     pub preferred_label_values: Option<PreferredLabelValues>,
 }
 
-// use serde_json::to_string;
-// use std::collections::HashMap;
-
-// impl ReverseGeocodeParams {
-//     pub fn as_form_body(self) -> HashMap<&'static str, String> {
-//         let mut map = HashMap::new();
-//         map.insert("location", to_string(&self.location).unwrap());
-
-//         // insert spatialReference
-//         map.insert("outSR", to_string(&self.out_sr).unwrap());
-
-//         // inserts langCode if present
-//         self.lang_code
-//             .map(|lang_code| map.insert("langCode", to_string(&lang_code).unwrap()));
-
-//         // inserts forStorage if present
-//         self.for_storage
-//             .map(|for_storage| map.insert("forStorage", to_string(&for_storage).unwrap()));
-
-//         // inserts locationType if present
-//         self.location_type
-//             .map(|location_type| map.insert("locationType", to_string(&location_type).unwrap()));
-
-//         // inserts preferredLabelValues if present
-//         self.preferred_label_values.map(|preferred_label_values| {
-//             map.insert(
-//                 "preferredLabelValues",
-//                 to_string(&preferred_label_values).unwrap(),
-//             )
-//         });
-
-//         map
-//     }
-
-//     pub fn _new(x: f64, y: f64) -> Self {
-//         ReverseGeocodeParams {
-//             location: EsriPoint {
-//                 x,
-//                 y,
-//                 z: None,
-//                 m: None,
-//                 spatialReference: None,
-//             },
-//             out_sr: SpatialReference {
-//                 wkid: Some(3857),
-//                 latest_wkid: None,
-//                 vcs_wkid: None,
-//                 latest_vcs_wkid: None,
-//                 wkt: None,
-//             },
-//             lang_code: None,
-//             for_storage: None,
-//             feature_types: None,
-//             location_type: None,
-//             preferred_label_values: None,
-//         }
-//     }
-// }
+// Serializes a value for use in a form body.
+// Strings are not quoted, everything else is written as JSON
+fn form_value<T: Serialize>(x: &T) -> Option<String> {
+    match serde_json::to_value(x).ok()? {
+        Value::Null => None,
+        Value::String(s) => Some(s),
+        v => Some(v.to_string()),
+    }
+}
+
+// Parses one of the parameter enums from its string representation.
+// Values that are not a variant are an error instead of being dropped
+// so that the service never receives a request without the filter.
+fn parse_param<T: DeserializeOwned>(arg: &str, x: Option<String>) -> Converted<Option<T>> {
+    let x = match x {
+        Some(xi) => xi,
+        None => return Ok(None),
+    };
+
+    serde_json::from_value(Value::String(x.clone()))
+        .map(Some)
+        .map_err(|_| {
+            let mut e = ConversionError::new(arg, "is not a recognized value");
+            e.value = Some(format!("`{x}`"));
+            e
+        })
+}
+
+impl ReverseGeocodeParams {
+    pub fn as_form_body(&self) -> FormBody {
+        let mut body = vec![(String::from("f"), String::from("json"))];
+
+        let fields = [
+            ("location", form_value(&self.location)),
+            ("outSR", form_value(&self.out_sr)),
+            ("langCode", form_value(&self.lang_code)),
+            ("forStorage", form_value(&self.for_storage)),
+            ("featureTypes", form_value(&self.feature_types)),
+            ("locationType", form_value(&self.location_type)),
+            (
+                "preferredLabelValues",
+                form_value(&self.preferred_label_values),
+            ),
+        ];
+
+        // missing values are omitted from the request
+        for (key, val) in fields {
+            if let Some(v) = val {
+                body.push((key.to_string(), v));
+            }
+        }
+
+        body
+    }
+}
 
 impl Default for ReverseGeocodeParams {
     fn default() -> Self {
@@ -177,90 +136,223 @@ pub enum FeatureType {
     Locality,
 }
 
-// Expected Response from the /reverseGeocode Endpoint
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub struct ReverseGeocodeResponse {
-    pub address: Address,
-    pub location: EsriPoint,
+// The fields of the address of a /reverseGeocode response. Locators omit
+// the fields that they do not support so every field may be missing.
+// Attributes that are not one of these fields are added as extra columns.
+pub static ADDRESS_FIELDS: [AttributeField; 22] = [
+    AttributeField::new("Match_addr", "match_addr", Kind::Str),
+    AttributeField::new("LongLabel", "long_label", Kind::Str),
+    AttributeField::new("ShortLabel", "short_label", Kind::Str),
+    AttributeField::new("Addr_type", "addr_type", Kind::Str),
+    AttributeField::new("Type", "type_field", Kind::Str),
+    AttributeField::new("PlaceName", "place_name", Kind::Str),
+    AttributeField::new("AddNum", "add_num", Kind::Str),
+    AttributeField::new("Address", "address", Kind::Str),
+    AttributeField::new("Block", "block", Kind::Str),
+    AttributeField::new("Sector", "sector", Kind::Str),
+    AttributeField::new("Neighborhood", "neighborhood", Kind::Str),
+    AttributeField::new("District", "district", Kind::Str),
+    AttributeField::new("City", "city", Kind::Str),
+    AttributeField::new("MetroArea", "metro_area", Kind::Str),
+    AttributeField::new("Subregion", "subregion", Kind::Str),
+    AttributeField::new("Region", "region", Kind::Str),
+    AttributeField::new("RegionAbbr", "region_abbr", Kind::Str),
+    AttributeField::new("Territory", "territory", Kind::Str),
+    AttributeField::new("Postal", "postal", Kind::Str),
+    AttributeField::new("PostalExt", "postal_ext", Kind::Str),
+    AttributeField::new("CntryName", "country_name", Kind::Str),
+    AttributeField::new("CountryCode", "country_code", Kind::Str),
+];
+
+// Reads the raw JSON of each /reverseGeocode response into the row of its
+// location. Responses that are missing or could not be parsed leave their
+// row empty and the 1-based rows are returned in the `dropped` attribute.
+// The errors of the responses that could not be parsed are returned as well
+fn rev_geocode_resp_list(resps: &[Option<&str>]) -> (Robj, Vec<GeocodeError>) {
+    let mut results = Results::addresses(&ADDRESS_FIELDS[..], resps.len());
+    let mut errors = Vec::new();
+
+    for (i, body) in resps.iter().enumerate() {
+        if let Some(b) = body {
+            if let Err(e) = results.read_address(b, i) {
+                errors.push(GeocodeError::from_body(b, e).with_request(i as i32 + 1));
+            }
+        }
+    }
+
+    let dropped = (0..resps.len())
+        .filter(|&i| !results.is_filled(i))
+        .map(|i| i as i32 + 1)
+        .collect::<Vec<_>>();
+
+    let mut res = results.into_robj();
+    let _ = res.set_attrib("dropped", dropped);
+    (res, errors)
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize, IntoDataFrameRow)]
-#[serde(rename_all = "camelCase")]
-pub struct Address {
-    #[serde(rename = "Match_addr")]
-    pub match_addr: String,
-    #[serde(rename = "LongLabel")]
-    pub long_label: String,
-    #[serde(rename = "ShortLabel")]
-    pub short_label: String,
-    #[serde(rename = "Addr_type")]
-    pub addr_type: String,
-    #[serde(rename = "Type")]
-    pub type_field: String,
-    #[serde(rename = "PlaceName")]
-    pub place_name: String,
-    #[serde(rename = "AddNum")]
-    pub add_num: String,
-    #[serde(rename = "Address")]
-    pub address: String,
-    #[serde(rename = "Block")]
-    pub block: String,
-    #[serde(rename = "Sector")]
-    pub sector: String,
-    #[serde(rename = "Neighborhood")]
-    pub neighborhood: String,
-    #[serde(rename = "District")]
-    pub district: String,
-    #[serde(rename = "City")]
-    pub city: String,
-    #[serde(rename = "MetroArea")]
-    pub metro_area: String,
-    #[serde(rename = "Subregion")]
-    pub subregion: String,
-    #[serde(rename = "Region")]
-    pub region: String,
-    #[serde(rename = "RegionAbbr")]
-    pub region_abbr: String,
-    #[serde(rename = "Territory")]
-    pub territory: String,
-    #[serde(rename = "Postal")]
-    pub postal: String,
-    #[serde(rename = "PostalExt")]
-    pub postal_ext: String,
-    #[serde(rename = "CntryName")]
-    pub country_name: String,
-    #[serde(rename = "CountryCode")]
-    pub country_code: String,
+#[extendr]
+pub fn parse_rev_geocode_resp(resps: Strings) -> Robj {
+    let resps = resps.iter().map(|ri| Some(ri.as_str())).collect::<Vec<_>>();
+    let (mut res, errors) = rev_geocode_resp_list(&resps);
+    let _ = res.set_attrib("errors", errors_as_robj(&errors, "request"));
+    res
 }
 
+// Reverse geocodes locations, such as an `sfc` of points, WKB, geoarrow
+// points, or a list of `x`, `y`, and `z` coordinates, by sending one request
+// per point from Rust. `in_sr` may be `NULL` for geoarrow points that have a
+// `crs`. At most `max_active` requests are made concurrently. When `progress`
+// is true the number of completed requests is reported. Empty points are not
+// sent and their rows are returned in `skipped`.
 #[extendr]
-pub fn parse_rev_geocode_resp(resps: Strings) -> List {
-    let mut res_geo = List::new(resps.len());
+#[allow(clippy::too_many_arguments)]
+pub fn reverse_geocode_rs(
+    service_url: &str,
+    locations: Robj,
+    in_sr: Robj,
+    out_sr: Robj,
+    lang_code: Nullable<String>,
+    for_storage: Nullable<bool>,
+    feature_type: Nullable<String>,
+    location_type: Nullable<String>,
+    preferred_label_values: Nullable<String>,
+    token: Nullable<String>,
+    max_active: i32,
+    max_attempts: i32,
+    cache: Robj,
+    cassette: Robj,
+    progress: bool,
+) -> Robj {
+    let url = match endpoint_url(service_url, "reverseGeocode") {
+        Some(u) => u,
+        None => throw_r_error(format!("Invalid service url: `{service_url}`")),
+    };
 
-    let res_attrs = resps
+    let in_sr = convert::optional_spatial_reference("in_sr", &in_sr).or_throw();
+    let out_sr = convert::spatial_reference("out_sr", &out_sr).or_throw();
+    let max_active = convert::count("max_active", max_active).or_throw();
+    let max_attempts = convert::count("max_attempts", max_attempts).or_throw();
+
+    let lang_code = lang_code.into_option();
+    let for_storage = for_storage.into_option();
+    let feature_types =
+        parse_param::<FeatureType>("feature_type", feature_type.into_option()).or_throw();
+    let location_type =
+        parse_param::<LocationType>("location_type", location_type.into_option()).or_throw();
+    let preferred_label_values = parse_param::<PreferredLabelValues>(
+        "preferred_label_values",
+        preferred_label_values.into_option(),
+    )
+    .or_throw();
+
+    let locs = convert::locations("locations", locations, in_sr.as_ref()).or_throw();
+    let skipped = convert::empty_points(&locs);
+
+    // create the parameters for each location
+    let params = locs
         .into_iter()
+        .map(|loc| {
+            loc.map(|location| ReverseGeocodeParams {
+                location,
+                out_sr: out_sr.clone(),
+                lang_code: lang_code.clone(),
+                for_storage,
+                feature_types: feature_types.clone(),
+                location_type: location_type.clone(),
+                preferred_label_values: preferred_label_values.clone(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // responses that are found in the cache are not requested again
+    let mut cache = GeocodeCache::from_robj(&cache);
+    let url_str = url.to_string();
+    let keys = params
+        .iter()
+        .map(|p| match (p, cache.as_ref()) {
+            (Some(p), Some(_)) => Some(cache_key(&url_str, &[], p)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut bodies = keys
+        .iter()
+        .map(|k| {
+            let entry = cache.as_ref()?.get(k.as_ref()?)?;
+            Some(entry.value.to_string())
+        })
+        .collect::<Vec<_>>();
+
+    // locations that could not be converted are never sent
+    let to_send = params
+        .iter()
         .enumerate()
-        .map(|(i, ri)| {
-            let resp = serde_json::from_str::<ReverseGeocodeResponse>(ri.as_str());
-            let res = match resp {
-                Ok(r) => {
-                    // let res = to_robj(&r.address).unwrap().as_list().unwrap();
-                    let _ = res_geo.set_elt(i, crate::as_sfg(r.location));
-                    vec![r.address].into_dataframe().unwrap().as_robj().clone()
-                    // res.into_robj()
+        .filter(|(i, p)| p.is_some() && bodies[*i].is_none())
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+
+    // create a form body for each location
+    let forms = to_send
+        .iter()
+        .filter_map(|&i| params[i].as_ref().map(|p| p.as_form_body()))
+        .collect::<Vec<_>>();
+
+    let transport = Transport::new(url, token.into_option(), max_active)
+        .with_retry(RetryPolicy::new(max_attempts as u32))
+        .with_cassette(Cassette::from_robj(&cassette))
+        .with_progress(progress.then_some("Reverse geocoding"));
+
+    let replies = match transport.post_forms(forms) {
+        Ok(r) => r,
+        Err(e) => throw_r_error(format!("Failed to send requests: {e}")),
+    };
+
+    // results can only be persisted when obtained for storage
+    let storable = for_storage == Some(true);
+
+    for (&i, reply) in to_send.iter().zip(replies.iter()) {
+        bodies[i] = reply.success().map(String::from);
+
+        if !storable {
+            continue;
+        }
+
+        if let (Some(c), Some(key), Some(body)) =
+            (cache.as_mut(), keys[i].as_ref(), bodies[i].as_ref())
+        {
+            // only responses with an address are cached
+            if let Ok(value) = serde_json::from_str::<Value>(body) {
+                if value.get("address").map_or(false, Value::is_object) {
+                    c.insert(key.clone(), value, None);
                 }
-                Err(_) => ().into_robj(),
-            };
-            res
-        })
-        .collect::<List>()
-        .into();
+            }
+        }
+    }
+
+    if let Some(Err(e)) = cache.as_mut().map(|c| c.flush()) {
+        throw_r_error(format!("Failed to write to geocode cache: {e}"));
+    }
+
+    let bodies = bodies.iter().map(|b| b.as_deref()).collect::<Vec<_>>();
+    let (mut res, mut errors) = rev_geocode_resp_list(&bodies);
+
+    // requests that did not succeed
+    errors.extend(
+        to_send.iter().zip(replies.iter()).filter_map(|(&i, r)| {
+            GeocodeError::from_reply(r).map(|e| e.with_request(i as i32 + 1))
+        }),
+    );
+    errors.sort_by_key(|e| e.request);
 
-    List::from_names_and_values(&["attributes", "geometry"], [res_attrs, res_geo]).unwrap()
+    let _ = res.set_attrib("errors", errors_as_robj(&errors, "request"));
+    let attempts = attempts_as_robj(to_send.iter().map(|i| *i as i32 + 1).zip(replies.iter()));
+    let _ = res.set_attrib("attempts", attempts);
+    let _ = res.set_attrib("skipped", skipped);
+    res
 }
 
 extendr_module! {
     mod reverse;
     fn parse_rev_geocode_resp;
+    fn reverse_geocode_rs;
 }
@@ -0,0 +1,534 @@
+use extendr_api::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+// The type of a field as reported in the `candidateFields` of a locator.
+// Types that are not known are treated as strings.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum FieldType {
+    #[serde(rename = "esriFieldTypeSmallInteger")]
+    SmallInteger,
+    #[serde(rename = "esriFieldTypeInteger")]
+    Integer,
+    #[serde(rename = "esriFieldTypeBigInteger")]
+    BigInteger,
+    #[serde(rename = "esriFieldTypeOID")]
+    Oid,
+    #[serde(rename = "esriFieldTypeSingle")]
+    Single,
+    #[serde(rename = "esriFieldTypeDouble")]
+    Double,
+    #[serde(rename = "esriFieldTypeString")]
+    String,
+    #[serde(rename = "esriFieldTypeDate")]
+    Date,
+    #[serde(rename = "esriFieldTypeDateOnly")]
+    DateOnly,
+    #[serde(rename = "esriFieldTypeTimeOnly")]
+    TimeOnly,
+    #[serde(rename = "esriFieldTypeTimestampOffset")]
+    TimestampOffset,
+    #[serde(rename = "esriFieldTypeGUID")]
+    Guid,
+    #[serde(rename = "esriFieldTypeGlobalID")]
+    GlobalId,
+    #[serde(rename = "esriFieldTypeGeometry")]
+    Geometry,
+    #[serde(rename = "esriFieldTypeBlob")]
+    Blob,
+    #[serde(rename = "esriFieldTypeRaster")]
+    Raster,
+    #[serde(rename = "esriFieldTypeXML")]
+    Xml,
+    #[serde(other)]
+    Unknown,
+}
+
+impl FieldType {
+    pub fn from_esri(x: &str) -> Self {
+        serde_json::from_value(Value::String(x.to_string())).unwrap_or(FieldType::Unknown)
+    }
+
+    // BigIntegers are doubles as R has no 64-bit integer type. Times and
+    // timestamps with an offset are kept as their string representation.
+    fn column_type(&self) -> ColumnType {
+        match self {
+            FieldType::SmallInteger | FieldType::Integer | FieldType::Oid => ColumnType::Integer,
+            FieldType::BigInteger | FieldType::Single | FieldType::Double => ColumnType::Double,
+            FieldType::Date => ColumnType::Date,
+            FieldType::DateOnly => ColumnType::DateOnly,
+            FieldType::String
+            | FieldType::TimeOnly
+            | FieldType::TimestampOffset
+            | FieldType::Guid
+            | FieldType::GlobalId
+            | FieldType::Geometry
+            | FieldType::Blob
+            | FieldType::Raster
+            | FieldType::Xml
+            | FieldType::Unknown => ColumnType::Character,
+        }
+    }
+}
+
+// A field of the `addressFields` or `candidateFields` of a locator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub field_type: FieldType,
+    #[serde(default)]
+    pub alias: Option<String>,
+    #[serde(default)]
+    pub required: bool,
+    #[serde(default)]
+    pub length: Option<i32>,
+}
+
+impl Field {
+    pub fn new(name: String, field_type: FieldType) -> Self {
+        Field {
+            name,
+            field_type,
+            alias: None,
+            required: false,
+            length: None,
+        }
+    }
+}
+
+// The fields of a locator in the order that they are returned
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    pub fields: Vec<Field>,
+}
+
+impl Schema {
+    // Creates a schema from the `name` and `type` columns of a data.frame
+    // such as the `candidateFields` of a `GeocodeServer`. Repeated names
+    // are only used once.
+    pub fn from_list(x: &List) -> Self {
+        let column = |nm: &str| {
+            x.dollar(nm)
+                .ok()
+                .and_then(|col| Strings::try_from(col).ok())
+                .map(|col| col.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+
+        let mut fields: Vec<Field> = Vec::new();
+        for (name, ftype) in column("name").into_iter().zip(column("type")) {
+            if fields.iter().any(|f| f.name == name) {
+                continue;
+            }
+            fields.push(Field::new(name, FieldType::from_esri(&ftype)));
+        }
+
+        Schema { fields }
+    }
+
+    pub fn from_fields(x: &[Field]) -> Self {
+        let mut fields: Vec<Field> = Vec::with_capacity(x.len());
+        for f in x {
+            if !fields.iter().any(|fi| fi.name == f.name) {
+                fields.push(f.clone());
+            }
+        }
+        Schema { fields }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ColumnType {
+    Logical,
+    Integer,
+    Double,
+    Date,
+    DateOnly,
+    Character,
+}
+
+impl ColumnType {
+    // The type of a field that is not part of the schema
+    fn infer(x: &Value) -> Option<Self> {
+        match x {
+            Value::Null => None,
+            Value::Bool(_) => Some(ColumnType::Logical),
+            Value::Number(_) => Some(ColumnType::Double),
+            _ => Some(ColumnType::Character),
+        }
+    }
+}
+
+// The values of a single column. Fields that are not part of the schema and
+// have only been missing so far are `Pending` until their type is known.
+#[derive(Debug)]
+enum Column {
+    Pending(usize),
+    Logical(Vec<Rbool>),
+    Integer(Vec<Rint>),
+    Double(Vec<Rfloat>),
+    // seconds since the epoch
+    Date(Vec<Rfloat>),
+    // days since the epoch
+    DateOnly(Vec<Rfloat>),
+    Character(Vec<Option<String>>),
+}
+
+// The result of coercing a value that is not missing to the type of a column
+enum Coerced<T> {
+    Value(T),
+    // the value cannot be represented by the column
+    Invalid,
+}
+
+fn is_missing(x: &Value) -> bool {
+    match x {
+        Value::Null => true,
+        Value::String(s) => s.trim().is_empty(),
+        _ => false,
+    }
+}
+
+fn as_logical(x: &Value) -> Coerced<bool> {
+    match x {
+        Value::Bool(b) => Coerced::Value(*b),
+        Value::Number(n) => match n.as_f64() {
+            Some(f) => Coerced::Value(f != 0.0),
+            None => Coerced::Invalid,
+        },
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "true" => Coerced::Value(true),
+            "false" => Coerced::Value(false),
+            _ => Coerced::Invalid,
+        },
+        _ => Coerced::Invalid,
+    }
+}
+
+fn as_double(x: &Value) -> Coerced<f64> {
+    let v = match x {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    };
+
+    match v {
+        Some(f) => Coerced::Value(f),
+        None => Coerced::Invalid,
+    }
+}
+
+// Integers that do not fit into a 32-bit integer are `Invalid`.
+// These are stored by promoting the column to a double.
+fn as_integer(x: &Value) -> Coerced<i32> {
+    let v = match x {
+        Value::Number(n) => n
+            .as_i64()
+            .or_else(|| n.as_f64().filter(|f| f.fract() == 0.0).map(|f| f as i64)),
+        Value::String(s) => s.trim().parse::<i64>().ok(),
+        _ => None,
+    };
+
+    match v.and_then(|vi| i32::try_from(vi).ok()) {
+        Some(i) => Coerced::Value(i),
+        None => Coerced::Invalid,
+    }
+}
+
+// Days since the epoch of the proleptic Gregorian calendar
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// Dates are `YYYY-MM-DD` strings or milliseconds since the epoch
+fn as_date_only(x: &Value) -> Coerced<f64> {
+    if let Value::Number(n) = x {
+        return match n.as_f64() {
+            Some(ms) => Coerced::Value((ms / 86_400_000.0).floor()),
+            None => Coerced::Invalid,
+        };
+    }
+
+    let parts = x
+        .as_str()
+        .map(|s| {
+            s.trim()
+                .splitn(3, '-')
+                .map(|p| p.parse::<i64>().ok())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    match parts[..] {
+        [Some(y), Some(m), Some(d)] if (1..=12).contains(&m) && (1..=31).contains(&d) => {
+            Coerced::Value(days_from_civil(y, m, d) as f64)
+        }
+        _ => Coerced::Invalid,
+    }
+}
+
+fn as_character(x: &Value) -> Option<String> {
+    match x {
+        Value::Null => None,
+        Value::String(s) if s.is_empty() => None,
+        Value::String(s) => Some(s.clone()),
+        other => Some(other.to_string()),
+    }
+}
+
+// Pushes a coerced value, returning `false` if it was invalid
+fn push_coerced<T, R>(
+    v: &mut Vec<R>,
+    missing: bool,
+    x: impl FnOnce() -> Coerced<T>,
+    na: R,
+    f: impl Fn(T) -> R,
+) -> bool {
+    if missing {
+        v.push(na);
+        return true;
+    }
+
+    match x() {
+        Coerced::Value(xi) => {
+            v.push(f(xi));
+            true
+        }
+        Coerced::Invalid => {
+            v.push(na);
+            false
+        }
+    }
+}
+
+impl Column {
+    fn new(ctype: ColumnType, n: usize) -> Self {
+        match ctype {
+            ColumnType::Logical => Column::Logical(vec![Rbool::na(); n]),
+            ColumnType::Integer => Column::Integer(vec![Rint::na(); n]),
+            ColumnType::Double => Column::Double(vec![Rfloat::na(); n]),
+            ColumnType::Date => Column::Date(vec![Rfloat::na(); n]),
+            ColumnType::DateOnly => Column::DateOnly(vec![Rfloat::na(); n]),
+            ColumnType::Character => Column::Character(vec![None; n]),
+        }
+    }
+
+    // Converts an integer column into a double column
+    fn promote(&mut self) {
+        if let Column::Integer(v) = self {
+            let promoted = v
+                .iter()
+                .map(|vi| match vi.is_na() {
+                    true => Rfloat::na(),
+                    false => Rfloat::from(vi.inner() as f64),
+                })
+                .collect();
+            *self = Column::Double(promoted);
+        }
+    }
+
+    // Adds a value to the column. Values are coerced to the type of the
+    // column. Returns `false` when a value could not be represented and
+    // was stored as missing.
+    fn push(&mut self, x: Option<&Value>) -> bool {
+        let x = x.unwrap_or(&Value::Null);
+
+        if let Column::Pending(n) = *self {
+            match ColumnType::infer(x) {
+                Some(ctype) => *self = Column::new(ctype, n),
+                None => {
+                    *self = Column::Pending(n + 1);
+                    return true;
+                }
+            }
+        }
+
+        let missing = is_missing(x);
+
+        // integers that are too large or have a fraction are kept as doubles
+        if let Column::Integer(_) = self {
+            let is_invalid = matches!(as_integer(x), Coerced::Invalid);
+            if !missing && is_invalid && matches!(as_double(x), Coerced::Value(_)) {
+                self.promote();
+            }
+        }
+
+        match self {
+            Column::Pending(_) => true,
+            Column::Logical(v) => {
+                push_coerced(v, missing, || as_logical(x), Rbool::na(), Rbool::from)
+            }
+            Column::Integer(v) => {
+                push_coerced(v, missing, || as_integer(x), Rint::na(), Rint::from)
+            }
+            Column::Double(v) => {
+                push_coerced(v, missing, || as_double(x), Rfloat::na(), Rfloat::from)
+            }
+            // dates are returned as milliseconds since the epoch
+            Column::Date(v) => push_coerced(
+                v,
+                missing,
+                || as_double(x),
+                Rfloat::na(),
+                |ms| Rfloat::from(ms / 1000.0),
+            ),
+            Column::DateOnly(v) => {
+                push_coerced(v, missing, || as_date_only(x), Rfloat::na(), Rfloat::from)
+            }
+            Column::Character(v) => {
+                v.push(as_character(x));
+                true
+            }
+        }
+    }
+
+    fn into_robj(self) -> Robj {
+        match self {
+            Column::Pending(n) => Logicals::from_values(vec![Rbool::na(); n]).into_robj(),
+            Column::Logical(v) => Logicals::from_values(v).into_robj(),
+            Column::Integer(v) => Integers::from_values(v).into_robj(),
+            Column::Double(v) => Doubles::from_values(v).into_robj(),
+            Column::Date(v) => {
+                let mut res = Doubles::from_values(v).into_robj();
+                let _ = res.set_attrib("tzone", "UTC");
+                let _ = res.set_class(&["POSIXct", "POSIXt"]);
+                res
+            }
+            Column::DateOnly(v) => {
+                let mut res = Doubles::from_values(v).into_robj();
+                let _ = res.set_class(&["Date"]);
+                res
+            }
+            Column::Character(v) => v
+                .into_iter()
+                .map(|vi| match vi {
+                    Some(s) => Rstr::from(s),
+                    None => Rstr::na(),
+                })
+                .collect::<Strings>()
+                .into_robj(),
+        }
+    }
+}
+
+// A value that could not be represented by the type of its field
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub row: usize,
+    pub field: String,
+    pub value: String,
+}
+
+// Builds a data.frame from the attributes of a response one row at a time.
+// Every field of the schema is a column of its declared type. Fields that
+// are not part of the schema are added as columns when they are first
+// encountered so that they are never dropped.
+#[derive(Debug)]
+pub struct Decoder {
+    names: Vec<String>,
+    columns: Vec<Column>,
+    index: HashMap<String, usize>,
+    n: usize,
+    problems: Vec<Problem>,
+}
+
+impl Decoder {
+    pub fn new(schema: &Schema) -> Self {
+        let mut decoder = Decoder {
+            names: Vec::with_capacity(schema.fields.len()),
+            columns: Vec::with_capacity(schema.fields.len()),
+            index: HashMap::new(),
+            n: 0,
+            problems: Vec::new(),
+        };
+
+        for field in schema.fields.iter() {
+            decoder.add_column(&field.name, Column::new(field.field_type.column_type(), 0));
+        }
+
+        decoder
+    }
+
+    fn add_column(&mut self, name: &str, column: Column) {
+        self.index.insert(name.to_string(), self.columns.len());
+        self.names.push(name.to_string());
+        self.columns.push(column);
+    }
+
+    // Adds a row. Columns that the row does not contain are missing
+    pub fn push(&mut self, row: Option<&Map<String, Value>>) {
+        if let Some(r) = row {
+            for key in r.keys() {
+                if !self.index.contains_key(key) {
+                    self.add_column(key, Column::Pending(self.n));
+                }
+            }
+        }
+
+        for (name, col) in self.names.iter().zip(self.columns.iter_mut()) {
+            let value = row.and_then(|r| r.get(name));
+            if !col.push(value) {
+                self.problems.push(Problem {
+                    row: self.n + 1,
+                    field: name.clone(),
+                    value: value.map(|v| v.to_string()).unwrap_or_default(),
+                });
+            }
+        }
+
+        self.n += 1;
+    }
+
+    // The data.frame of the decoded rows. Values that could not be
+    // represented are missing and are described by the `problems` attribute
+    pub fn finish(self) -> Robj {
+        let n = self.n as i32;
+        let problems = match self.problems.is_empty() {
+            true => None,
+            false => Some(problems_as_robj(&self.problems)),
+        };
+        let columns = self.columns.into_iter().map(|c| c.into_robj());
+
+        let mut res = match List::from_names_and_values(self.names, columns) {
+            Ok(l) => l.into_robj(),
+            Err(_) => List::new(0).into_robj(),
+        };
+
+        let _ = res.set_attrib(
+            "row.names",
+            Integers::from_values([Rint::na(), Rint::from(-n)]),
+        );
+        let _ = res.set_class(&["data.frame"]);
+        if let Some(p) = problems {
+            let _ = res.set_attrib("problems", p);
+        }
+        res
+    }
+}
+
+// The row, field, and value of each problem as a list of columns
+pub fn problems_as_robj(problems: &[Problem]) -> Robj {
+    list!(
+        row = problems
+            .iter()
+            .map(|p| Rint::from(p.row as i32))
+            .collect::<Integers>(),
+        field = problems
+            .iter()
+            .map(|p| p.field.as_str())
+            .collect::<Strings>(),
+        value = problems
+            .iter()
+            .map(|p| p.value.as_str())
+            .collect::<Strings>()
+    )
+    .into_robj()
+}
@@ -1,3 +1,5 @@
+use crate::convert;
+use crate::error::GeocodeError;
 use extendr_api::prelude::*;
 use serde::{Deserialize, Serialize};
 
@@ -18,8 +20,8 @@ pub struct Suggestions {
 pub fn parse_suggestions(x: &str) -> Robj {
     let sugg = serde_json::from_str::<Suggestions>(x);
     match sugg {
-        Ok(s) => Dataframe::try_from_values(s.suggestions).unwrap().as_robj().clone(),
-        Err(_) => Dataframe::try_from_values(Suggestions::default().suggestions).unwrap().as_robj().clone(),
+        Ok(s) => convert::dataframe(s.suggestions),
+        Err(e) => GeocodeError::from_body(x, e).into_robj(),
     }
 }
 
@@ -0,0 +1,438 @@
+use crate::cassette::Cassette;
+use crate::convert::{self, ConversionError, OrThrow};
+use crate::error::{errors_as_robj, GeocodeError};
+use crate::retry::{classify, is_transient_error, Attempt, RetryPolicy, Verdict};
+use extendr_api::prelude::*;
+use reqwest::{header::RETRY_AFTER, Client, Url};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::{runtime::Builder, sync::Semaphore};
+
+// How long a request may take, including reading its body, and how long
+// establishing a connection may take. Requests that exceed these are
+// timeouts which are retried.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(300);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Form bodies are sent as a vector of key-value pairs
+// this keeps the order of the parameters stable
+pub type FormBody = Vec<(String, String)>;
+
+// The number of bytes of `x` once it is `application/x-www-form-urlencoded`.
+// Spaces become `+` and every byte other than `*-._` and alphanumerics
+// is percent encoded.
+pub fn encoded_len(x: &str) -> usize {
+    x.bytes()
+        .map(|b| match b {
+            b'*' | b'-' | b'.' | b'_' | b' ' => 1,
+            b if b.is_ascii_alphanumeric() => 1,
+            _ => 3,
+        })
+        .sum()
+}
+
+// The size in bytes of the body of a form
+pub fn form_size(form: &FormBody) -> usize {
+    let pairs = form
+        .iter()
+        .map(|(k, v)| encoded_len(k) + 1 + encoded_len(v))
+        .sum::<usize>();
+    pairs + form.len().saturating_sub(1)
+}
+
+// Joins an endpoint onto the url of a GeocodeServer
+pub fn endpoint_url(service_url: &str, endpoint: &str) -> Option<Url> {
+    let url = format!("{}/{endpoint}", service_url.trim_end_matches('/'));
+    Url::parse(&url).ok()
+}
+
+// The outcome of a request after all of its attempts
+#[derive(Debug, Clone, Default)]
+pub struct Reply {
+    // the body of the last response. None if there never was one
+    pub body: Option<String>,
+    pub succeeded: bool,
+    // diagnostics of each attempt that did not succeed
+    pub attempts: Vec<Attempt>,
+}
+
+impl Reply {
+    // The body of a successful response
+    pub fn success(&self) -> Option<&str> {
+        if self.succeeded {
+            self.body.as_deref()
+        } else {
+            None
+        }
+    }
+}
+
+// Creates a list of the diagnostics of every failed attempt.
+// `request` identifies the request that the reply belongs to.
+// This is turned into a data.frame in R.
+pub fn attempts_as_robj<'a>(replies: impl IntoIterator<Item = (i32, &'a Reply)>) -> Robj {
+    let attempts = replies
+        .into_iter()
+        .flat_map(|(i, r)| r.attempts.iter().map(move |a| (i, a)))
+        .collect::<Vec<_>>();
+
+    let request = attempts.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+    let attempt = attempts
+        .iter()
+        .map(|(_, a)| a.attempt as i32)
+        .collect::<Vec<_>>();
+    let status = attempts
+        .iter()
+        .map(|(_, a)| Rint::from(a.status.map(|s| s as i32)))
+        .collect::<Integers>();
+    let code = attempts
+        .iter()
+        .map(|(_, a)| Rint::from(a.code))
+        .collect::<Integers>();
+    let extended_code = attempts
+        .iter()
+        .map(|(_, a)| Rint::from(a.extended_code))
+        .collect::<Integers>();
+    let message = attempts
+        .iter()
+        .map(|(_, a)| a.message.as_str())
+        .collect::<Strings>();
+    let delay = attempts
+        .iter()
+        .map(|(_, a)| Rfloat::from(a.delay.map(|d| d.as_secs_f64())))
+        .collect::<Doubles>();
+
+    list!(
+        request = request,
+        attempt = attempt,
+        status = status,
+        code = code,
+        extended_code = extended_code,
+        message = message,
+        delay = delay
+    )
+    .into_robj()
+}
+
+// Reports the number of completed requests on the R console.
+// Requests run on a current thread runtime so this is only ever
+// written to from the R main thread.
+#[derive(Debug)]
+struct Progress {
+    label: String,
+    total: usize,
+    done: AtomicUsize,
+}
+
+impl Progress {
+    fn tick(&self) {
+        let done = self.done.fetch_add(1, Ordering::Relaxed) + 1;
+        reprint!("\r{}: {done}/{}", self.label, self.total);
+        if done == self.total {
+            reprint!("\n");
+        }
+    }
+}
+
+// Sends POST requests to a single endpoint
+#[derive(Debug, Clone)]
+pub struct Transport {
+    pub url: Url,
+    pub token: Option<String>,
+    // the maximum number of concurrent requests
+    pub max_active: usize,
+    pub retry: RetryPolicy,
+    // records or replays the traffic
+    pub cassette: Option<Arc<Cassette>>,
+    // the label of the progress that is reported, if any
+    pub progress: Option<String>,
+}
+
+impl Transport {
+    pub fn new(url: Url, token: Option<String>, max_active: usize) -> Self {
+        Transport {
+            url,
+            token,
+            max_active: max_active.max(1),
+            retry: RetryPolicy::default(),
+            cassette: None,
+            progress: None,
+        }
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn with_cassette(mut self, cassette: Option<Cassette>) -> Self {
+        self.cassette = cassette.map(Arc::new);
+        self
+    }
+
+    pub fn with_progress(mut self, label: Option<&str>) -> Self {
+        self.progress = label.map(String::from);
+        self
+    }
+
+    // Sends each form body as a POST request.
+    // The replies are returned in the same order as `forms`
+    pub async fn post_forms_(&self, client: Client, forms: Vec<FormBody>) -> Vec<Reply> {
+        let ctx = Arc::new(self.clone());
+        let permits = Arc::new(Semaphore::new(self.max_active));
+        let progress = self.progress.as_ref().map(|label| {
+            Arc::new(Progress {
+                label: label.clone(),
+                total: forms.len(),
+                done: AtomicUsize::new(0),
+            })
+        });
+
+        let mut tasks = Vec::with_capacity(forms.len());
+
+        // create a task for each form body
+        for form in forms {
+            let client = client.clone();
+            let ctx = ctx.clone();
+            let permits = permits.clone();
+            let progress = progress.clone();
+
+            let task = async move {
+                // the permit is held until the body has been read
+                let _permit = permits.acquire_owned().await;
+                let reply = ctx.post_with_retry(&client, &form).await;
+                if let Some(p) = progress {
+                    p.tick();
+                }
+                reply
+            };
+
+            tasks.push(tokio::spawn(task));
+        }
+
+        // create a vector to store the output
+        let mut outputs = Vec::with_capacity(tasks.len());
+
+        // capture the output of each task
+        for task in tasks {
+            match task.await {
+                Ok(res) => outputs.push(res),
+                // tasks are never cancelled so a join error is a panic
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            }
+        }
+
+        outputs
+    }
+
+    // Blocking wrapper around `post_forms_()` so that it can be called
+    // from an extendr function. Recorded traffic is written to the cassette
+    // once every request has completed.
+    pub fn post_forms(&self, forms: Vec<FormBody>) -> std::io::Result<Vec<Reply>> {
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+        let rt = Builder::new_current_thread().enable_all().build()?;
+        let replies = rt.block_on(self.post_forms_(client, forms));
+
+        if let Some(c) = self.cassette.as_ref() {
+            c.flush()?;
+        }
+
+        Ok(replies)
+    }
+
+    async fn post_once(
+        &self,
+        client: &Client,
+        form: &FormBody,
+    ) -> std::result::Result<(u16, Option<Duration>, String), reqwest::Error> {
+        let mut req = client
+            .post(self.url.clone())
+            .header("User-Agent", "arcgisgeocode")
+            .form(form);
+
+        if let Some(t) = self.token.as_ref() {
+            req = req.header("X-Esri-Authorization", format!("Bearer {t}"));
+        }
+
+        let resp = req.send().await?;
+        let status = resp.status().as_u16();
+
+        // only the delay-seconds form of Retry-After is used
+        let retry_after = resp
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+
+        let body = resp.text().await?;
+        Ok((status, retry_after, body))
+    }
+
+    // Sends a request until it succeeds, fails with an error that cannot
+    // be recovered from, or the retry policy is exhausted.
+    async fn post_with_retry(&self, client: &Client, form: &FormBody) -> Reply {
+        let mut reply = Reply::default();
+        let cassette = self.cassette.as_deref();
+        let replaying = cassette.map_or(false, |c| c.is_replay());
+        let url = self.url.as_str();
+        let token = self.token.as_deref();
+
+        for attempt in 1..=self.retry.max_attempts {
+            let sent = if replaying {
+                match cassette.and_then(|c| c.replay(url, form, token)) {
+                    Some(r) => Ok(r),
+                    None => {
+                        reply.attempts.push(Attempt {
+                            attempt,
+                            message: format!("No recorded response to request for `{url}`"),
+                            ..Default::default()
+                        });
+                        return reply;
+                    }
+                }
+            } else {
+                self.post_once(client, form).await
+            };
+
+            let (mut diagnostic, wait) = match sent {
+                Ok((status, retry_after, body)) => {
+                    if let Some(c) = cassette.filter(|_| !replaying) {
+                        c.record(url, form, token, status, retry_after, &body);
+                    }
+
+                    let verdict = classify(status, retry_after, &body);
+                    let diagnostic = Attempt::from_response(attempt, status, &body);
+                    reply.body = Some(body);
+
+                    match verdict {
+                        Verdict::Success => {
+                            reply.succeeded = true;
+                            return reply;
+                        }
+                        Verdict::Fatal => {
+                            reply.attempts.push(diagnostic);
+                            return reply;
+                        }
+                        Verdict::Transient(retry_after) => (diagnostic, retry_after),
+                    }
+                }
+                Err(e) => {
+                    let diagnostic = Attempt::from_error(attempt, &e);
+                    reply.body = None;
+                    if !is_transient_error(&e) {
+                        reply.attempts.push(diagnostic);
+                        return reply;
+                    }
+                    (diagnostic, None)
+                }
+            };
+
+            // wait before the next attempt unless this was the last one
+            if attempt < self.retry.max_attempts {
+                // replayed responses are served without waiting
+                let delay = if replaying {
+                    Duration::ZERO
+                } else {
+                    self.retry.delay(attempt, wait)
+                };
+                diagnostic.delay = Some(delay);
+                reply.attempts.push(diagnostic);
+                tokio::time::sleep(delay).await;
+            } else {
+                reply.attempts.push(diagnostic);
+            }
+        }
+
+        reply
+    }
+}
+
+// Sends a form body to an endpoint of a GeocodeServer for each element of
+// `forms`, a list of named character vectors. Missing values are omitted.
+// Returns the body of the last response to each request, NA if there was
+// none, alongside the diagnostics of the failed attempts. Bodies are parsed
+// in R so `errors` only contains the requests that have no body.
+#[extendr]
+fn post_forms_rs(
+    service_url: &str,
+    endpoint: &str,
+    forms: List,
+    token: Nullable<String>,
+    max_active: i32,
+    max_attempts: i32,
+    cassette: Robj,
+) -> List {
+    let url = match endpoint_url(service_url, endpoint) {
+        Some(u) => u,
+        None => throw_r_error(format!("Invalid service url: `{service_url}`")),
+    };
+
+    let forms = forms
+        .values()
+        .enumerate()
+        .map(|(i, form)| {
+            let vals = match Strings::try_from(form.clone()) {
+                Ok(v) => v,
+                Err(_) => {
+                    let e = ConversionError::new("forms", "must be a named character vector");
+                    throw_r_error(e.at(i).found(&form).to_string())
+                }
+            };
+            match vals.names() {
+                Some(nms) => nms
+                    .zip(vals.iter())
+                    .filter(|(_, v)| !v.is_na())
+                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                    .collect::<FormBody>(),
+                None => FormBody::new(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let max_active = convert::count("max_active", max_active).or_throw();
+    let max_attempts = convert::count("max_attempts", max_attempts).or_throw();
+
+    let transport = Transport::new(url, token.into_option(), max_active)
+        .with_retry(RetryPolicy::new(max_attempts as u32))
+        .with_cassette(Cassette::from_robj(&cassette));
+
+    let replies = match transport.post_forms(forms) {
+        Ok(r) => r,
+        Err(e) => throw_r_error(format!("Failed to send requests: {e}")),
+    };
+
+    let bodies = replies
+        .iter()
+        .map(|r| match r.body.as_deref() {
+            Some(b) => Rstr::from(b),
+            None => Rstr::na(),
+        })
+        .collect::<Strings>();
+
+    let attempts = attempts_as_robj(replies.iter().enumerate().map(|(i, r)| (i as i32 + 1, r)));
+
+    let errors = replies
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| r.body.is_none())
+        .filter_map(|(i, r)| GeocodeError::from_reply(r).map(|e| e.with_request(i as i32 + 1)))
+        .collect::<Vec<_>>();
+
+    list!(
+        bodies = bodies,
+        errors = errors_as_robj(&errors, "request"),
+        attempts = attempts
+    )
+}
+
+extendr_module! {
+    mod transport;
+    fn post_forms_rs;
+}
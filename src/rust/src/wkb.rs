@@ -0,0 +1,119 @@
+// Reads points from well-known binary. Both ISO WKB and the extended WKB
+// written by PostGIS and GEOS are supported.
+
+// The coordinates of a point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coords {
+    pub x: f64,
+    pub y: f64,
+    pub z: Option<f64>,
+    pub m: Option<f64>,
+}
+
+// The name of a geometry type code used in errors
+fn type_name(code: u32) -> String {
+    match code {
+        1 => "POINT".to_string(),
+        2 => "LINESTRING".to_string(),
+        3 => "POLYGON".to_string(),
+        4 => "MULTIPOINT".to_string(),
+        5 => "MULTILINESTRING".to_string(),
+        6 => "MULTIPOLYGON".to_string(),
+        7 => "GEOMETRYCOLLECTION".to_string(),
+        c => format!("geometry of type {c}"),
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    little_endian: bool,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + N)
+            .ok_or_else(|| "is truncated WKB".to_string())?;
+        self.pos += N;
+        let mut out = [0; N];
+        out.copy_from_slice(bytes);
+        Ok(out)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take::<4>()?;
+        Ok(if self.little_endian {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    fn f64(&mut self) -> Result<f64, String> {
+        let b = self.take::<8>()?;
+        Ok(if self.little_endian {
+            f64::from_le_bytes(b)
+        } else {
+            f64::from_be_bytes(b)
+        })
+    }
+}
+
+// Parses a WKB point. Empty points, whose coordinates are NaN, are `None`.
+// Other geometry types are an error describing the type that was found.
+pub fn parse_point(buf: &[u8]) -> Result<Option<Coords>, String> {
+    let little_endian = match buf.first() {
+        Some(0) => false,
+        Some(1) => true,
+        _ => return Err("is not valid WKB".to_string()),
+    };
+
+    let mut rdr = Reader {
+        buf,
+        pos: 1,
+        little_endian,
+    };
+
+    let code = rdr.u32()?;
+
+    // extended WKB stores the dimensions and SRID as flags
+    let has_srid = code & 0x2000_0000 != 0;
+    let ewkb_z = code & 0x8000_0000 != 0;
+    let ewkb_m = code & 0x4000_0000 != 0;
+
+    // ISO WKB adds 1000, 2000, or 3000 to the type for Z, M, and ZM
+    let iso = code & 0x0FFF_FFFF;
+    let (has_z, has_m) = match iso / 1000 {
+        1 => (true, false),
+        2 => (false, true),
+        3 => (true, true),
+        _ => (ewkb_z, ewkb_m),
+    };
+
+    let geom_type = iso % 1000;
+    if geom_type != 1 {
+        return Err(format!("must be a POINT not a {}", type_name(geom_type)));
+    }
+
+    if has_srid {
+        rdr.u32()?;
+    }
+
+    let x = rdr.f64()?;
+    let y = rdr.f64()?;
+    let z = if has_z { Some(rdr.f64()?) } else { None };
+    let m = if has_m { Some(rdr.f64()?) } else { None };
+
+    if x.is_nan() || y.is_nan() {
+        return Ok(None);
+    }
+
+    Ok(Some(Coords {
+        x,
+        y,
+        z: z.filter(|v| !v.is_nan()),
+        m: m.filter(|v| !v.is_nan()),
+    }))
+}